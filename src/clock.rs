@@ -0,0 +1,114 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Clock: working out the time to send to the watch
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+use std::time::Duration;
+use chrono::{DateTime, FixedOffset, Local, Offset, TimeDelta, Utc};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time;
+
+
+// The watch keeps time as a timestamp in China Standard Time (GMT+8), whatever the local timezone
+const WATCH_TZ_HOURS: i32 = 8;
+
+const NTP_PORT: u16 = 123;
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;            // Seconds from 1900 (NTP epoch) to 1970 (Unix epoch)
+
+//
+// Parse a timezone offset such as +10, +10:00, -0530 or Z
+//
+pub fn parse_tz(s: &str) -> Result<FixedOffset, Box<dyn Error>> {
+    let bad = || format!("Invalid timezone offset '{}', expected e.g. +10:00", s);
+    if s == "Z" || s == "UTC" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+    let (sign, rest) = match s.chars().next() {
+        Some('+') => (1, &s[1..]),
+        Some('-') => (-1, &s[1..]),
+        _ => return Err(bad().into()),
+    };
+    let digits: String = rest.chars().filter(|c| *c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) || !(digits.len() == 2 || digits.len() == 4) {
+        return Err(bad().into());
+    }
+    let hours: i32 = digits[0..2].parse()?;
+    let minutes: i32 = if digits.len() == 4 { digits[2..4].parse()? } else { 0 };
+    if hours > 14 || minutes > 59 {
+        return Err(bad().into());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(|| bad().into())
+}
+
+//
+// The local timezone offset at the given time, including daylight saving
+//
+pub fn local_offset(t: DateTime<Utc>) -> FixedOffset {
+    t.with_timezone(&Local).offset().fix()
+}
+
+//
+// Payload for the set time command: the local wall clock time as a GMT+8 timestamp, then the timezone
+//
+pub fn time_payload(t: DateTime<Utc>, offset: FixedOffset) -> Vec<u8> {
+    let watch_time = t.timestamp() + offset.local_minus_utc() as i64 - (WATCH_TZ_HOURS * 3600) as i64;
+    let mut payload = (watch_time as u32).to_be_bytes().to_vec();
+    payload.push(WATCH_TZ_HOURS as u8);
+    payload
+}
+
+//
+// Ask an NTP server how far off the system clock is
+//
+pub async fn ntp_offset(server: &str) -> Result<TimeDelta, Box<dyn Error>> {
+    let server = if server.contains(':') { server.to_string() } else { format!("{}:{}", server, NTP_PORT) };
+    let addr = lookup_host(&server).await?.next().ok_or("Unable to resolve NTP server")?;
+    let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(addr).await?;
+
+    let mut request = [0u8; 48];
+    request[0] = 0x23;                                  // Leap indicator 0, version 4, mode 3 (client)
+    let t1 = Utc::now();
+    request[40..48].copy_from_slice(&to_ntp(t1));       // Transmit timestamp
+    socket.send(&request).await?;
+
+    let mut response = [0u8; 48];
+    let len = time::timeout(Duration::from_secs(5), socket.recv(&mut response)).await
+        .map_err(|_| "NTP server did not respond")??;
+    let t4 = Utc::now();
+    if len < 48 || response[0] & 0x07 != 4 || response[1] == 0 {
+        return Err("Invalid response from NTP server".into());
+    }
+    if response[24..32] != request[40..48] {
+        return Err("NTP response does not match our request".into());
+    }
+    let t2 = from_ntp(&response[32..40]).ok_or("Invalid receive timestamp from NTP server")?;
+    let t3 = from_ntp(&response[40..48]).ok_or("Invalid transmit timestamp from NTP server")?;
+    Ok(((t2 - t1) + (t3 - t4)) / 2)
+}
+
+fn to_ntp(t: DateTime<Utc>) -> [u8; 8] {
+    let secs = (t.timestamp() + NTP_UNIX_OFFSET) as u32;
+    let frac = ((t.timestamp_subsec_nanos() as u64) << 32) / 1_000_000_000;
+    let mut data = [0u8; 8];
+    data[0..4].copy_from_slice(&secs.to_be_bytes());
+    data[4..8].copy_from_slice(&(frac as u32).to_be_bytes());
+    data
+}
+
+fn from_ntp(data: &[u8]) -> Option<DateTime<Utc>> {
+    let secs = u32::from_be_bytes([ data[0], data[1], data[2], data[3] ]) as i64;
+    let frac = u32::from_be_bytes([ data[4], data[5], data[6], data[7] ]) as u64;
+    DateTime::from_timestamp(secs - NTP_UNIX_OFFSET, ((frac * 1_000_000_000) >> 32) as u32)
+}
+
+//
+// Wait for the start of the next whole second (of the corrected time), as the watch only keeps whole seconds
+//
+pub async fn next_second(correction: TimeDelta) -> DateTime<Utc> {
+    let now = Utc::now() + correction;
+    let wait = 1_000_000_000 - now.timestamp_subsec_nanos() as u64;
+    time::sleep(Duration::from_nanos(wait)).await;
+    Utc::now() + correction
+}
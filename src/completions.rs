@@ -0,0 +1,107 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Shell completions
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+use crate::config::Config;
+use crate::plugin;
+
+
+// Keep these in step with the modes and options in main()
+const MODES: [&str; 16] = [ "info", "upload", "agps", "abort", "set-time", "sync", "weather", "notify", "settings", "trace", "history", "listen", "errors", "script", "completions", "help" ];
+const OPTIONS: [&str; 28] = [ "name=", "address=", "verbosity=", "adapter=", "fileid=", "trafficlog=", "color=", "logfile=", "tz=", "ntp=",
+                              "every=", "actions=", "condition=", "temp=", "city=", "config=", "device=", "webhook=", "retries=", "timeout=", "manufacturer=", "force=", "lowbattery=", "journal=", "on-camera=", "on-find-phone=", "on-music=", "find-phone-sound=" ];
+// Actions, completed straight after their mode
+const ACTIONS: [(&str, &str); 5] = [ ("agps", "push"), ("notify", "test"), ("settings", "show apply export"), ("trace", "diff"),
+                                     ("completions", "bash zsh fish powershell") ];
+
+//
+// Completion script for the given shell
+//
+pub fn script(shell: &str) -> Result<String, Box<dyn Error>> {
+    let mut modes: Vec<&str> = MODES.to_vec();
+    modes.extend(plugin::plugins().iter().map(|p| p.name()));
+    let modes = modes.join(" ");
+    // device= aliases from the default config file, as it is when the script is made. Without a usable one, there are none.
+    let aliases: Vec<String> = Config::load(None).and_then(|c| c.devices()).map(|d| d.into_iter().map(|d| d.alias).collect()).unwrap_or_default();
+    let mut options: Vec<String> = OPTIONS.iter().map(|o| o.to_string()).collect();
+    options.extend(aliases.iter().map(|a| format!("device={}", a)));
+    let options = options.join(" ");
+    let aliases = aliases.join(" ");
+
+    let script = match shell {
+        "bash" => format!(r#"_dawfu() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [ "$COMP_CWORD" -eq 1 ]; then
+        COMPREPLY=( $(compgen -W "{modes}" -- "$cur") )
+        return
+    fi
+    if [ "$COMP_CWORD" -eq 2 ]; then
+        local actions=""
+        case "${{COMP_WORDS[1]}}" in
+{cases}        esac
+        if [ -n "$actions" ]; then
+            COMPREPLY=( $(compgen -W "$actions" -- "$cur") )
+            return
+        fi
+    fi
+    # Bash splits device=alias at the =, so the alias is completed as a word of its own
+    if [ "${{COMP_WORDS[COMP_CWORD-1]}}" = "device" ] && [ "$cur" = "=" ]; then
+        COMPREPLY=( $(compgen -P "=" -W "{aliases}") )
+        return
+    fi
+    if [ "$COMP_CWORD" -gt 2 ] && [ "${{COMP_WORDS[COMP_CWORD-2]}}" = "device" ] && [ "${{COMP_WORDS[COMP_CWORD-1]}}" = "=" ]; then
+        COMPREPLY=( $(compgen -W "{aliases}" -- "$cur") )
+        return
+    fi
+    compopt -o nospace
+    COMPREPLY=( $(compgen -W "{options}" -- "$cur") $(compgen -f -- "$cur") )
+}}
+complete -F _dawfu dawfu
+"#, modes = modes, options = options, aliases = aliases,
+            cases = ACTIONS.iter().map(|(mode, actions)| format!("            {}) actions=\"{}\" ;;\n", mode, actions)).collect::<String>()),
+        "zsh" => format!(r#"#compdef dawfu
+_dawfu() {{
+    if (( CURRENT == 2 )); then
+        compadd -- {modes}
+        return
+    fi
+    if (( CURRENT == 3 )); then
+        case $words[2] in
+{cases}        esac
+    fi
+    compadd -S '' -- {options}
+    _files
+}}
+compdef _dawfu dawfu
+"#, modes = modes, options = options,
+            cases = ACTIONS.iter().map(|(mode, actions)| format!("            {}) compadd -- {}; return ;;\n", mode, actions)).collect::<String>()),
+        "fish" => format!(r#"complete -c dawfu -f -n '__fish_use_subcommand' -a '{modes}'
+{actions}complete -c dawfu -n 'not __fish_use_subcommand' -a '{options}'
+"#, modes = modes, options = options,
+            actions = ACTIONS.iter().map(|(mode, actions)| format!(
+                "complete -c dawfu -f -n '__fish_seen_subcommand_from {}; and test (count (commandline -opc)) -eq 2' -a '{}'\n", mode, actions)).collect::<String>()),
+        "powershell" => format!(r#"Register-ArgumentCompleter -Native -CommandName dawfu -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $actions = @{{ {actions} }}
+    $count = $commandAst.CommandElements.Count
+    $position = if ($wordToComplete) {{ $count - 1 }} else {{ $count }}
+    $mode = if ($count -gt 1) {{ "$($commandAst.CommandElements[1])" }} else {{ '' }}
+    if ($position -eq 1) {{
+        $candidates = '{modes}' -split ' '
+    }} elseif ($position -eq 2 -and $actions.ContainsKey($mode)) {{
+        $candidates = $actions[$mode] -split ' '
+    }} else {{
+        $candidates = '{options}' -split ' '
+    }}
+    $candidates | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+"#, modes = modes, options = options,
+            actions = ACTIONS.iter().map(|(mode, actions)| format!("'{}' = '{}'", mode, actions)).collect::<Vec<String>>().join("; ")),
+        _ => return Err(format!("Unknown shell '{}', expected bash, zsh, fish or powershell", shell).into()),
+    };
+    Ok(script)
+}
@@ -0,0 +1,145 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Config file (TOML)
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::errors;
+use crate::protocol::ByteOrder;
+
+
+const CONFIG_FILENAME: &str = "dawfu.toml";
+
+pub struct Config {
+    table: toml::Table,
+}
+
+impl Config {
+    //
+    // Load the config file given with config=, or the default one if there is one
+    //
+    // The default is dawfu.toml in the current directory, then in the user config directory
+    // (~/.config/dawfu on Linux and macOS, %APPDATA%\dawfu on Windows).
+    //
+    pub fn load(filename: Option<&str>) -> Result<Config, Box<dyn Error>> {
+        let path = match filename {
+            Some(f) => Some(PathBuf::from(f)),
+            None => default_paths().into_iter().find(|p| p.is_file()),
+        };
+        let table = match path {
+            Some(p) => {
+                let text = std::fs::read_to_string(&p).map_err(|e| errors::coded("E030", format!("Unable to read config file {} ({})", p.display(), e)))?;
+                text.parse::<toml::Table>().map_err(|e| errors::coded("E030", format!("Error in config file {} ({})", p.display(), e)))?
+            },
+            None => toml::Table::new(),
+        };
+        Ok(Config { table })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&toml::Table> {
+        self.table.get(name).and_then(|v| v.as_table())
+    }
+
+    //
+    // Extra manufacturer strings to accept, e.g. manufacturers = [ "MOYOUNG-V3" ]
+    //
+    pub fn manufacturers(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let list = match self.table.get("manufacturers") {
+            Some(v) => v.as_array().ok_or("manufacturers in config file should be a list")?,
+            None => return Ok(Vec::new()),
+        };
+        list.iter().map(|v| v.as_str().map(|s| s.to_string()).ok_or_else(|| "manufacturers in config file should be strings".into())).collect()
+    }
+
+    //
+    // The [device.<alias>] sections
+    //
+    pub fn devices(&self) -> Result<Vec<Device>, Box<dyn Error>> {
+        let mut devices = Vec::new();
+        if let Some(section) = self.section("device") {
+            for (alias, value) in section {
+                let table = value.as_table().ok_or_else(|| format!("[device.{}] in config file should be a section", alias))?;
+                devices.push(Device::from_table(alias, table)?);
+            }
+        }
+        Ok(devices)
+    }
+
+    pub fn device(&self, alias: &str) -> Result<Device, Box<dyn Error>> {
+        self.devices()?.into_iter().find(|d| d.alias == alias)
+            .ok_or_else(|| format!("No [device.{}] section in config file", alias).into())
+    }
+
+    pub fn device_with_address(&self, address: &str) -> Result<Option<Device>, Box<dyn Error>> {
+        Ok(self.devices()?.into_iter().find(|d| d.address.as_deref().is_some_and(|a| a.eq_ignore_ascii_case(address))))
+    }
+}
+
+
+//
+// Per-device profile, e.g.
+//     [device.bedside]
+//     address = "01:23:45:67:89:ab"
+//     slot = 13               # Watch face slot to upload to (6 or 13)
+//     throttle = 20           # Milliseconds to wait after each chunk
+//     byte_order = "little"   # Byte order of chunk numbers and checksums (default: worked out during the transfer)
+//     [device.bedside.settings]
+//     time_format = "24h"
+//
+pub struct Device {
+    pub alias: String,
+    pub address: Option<String>,
+    pub name: Option<String>,
+    pub slot: Option<u8>,
+    pub throttle: Option<Duration>,
+    pub byte_order: Option<ByteOrder>,
+    pub settings: Option<toml::Table>,
+}
+
+impl Device {
+    fn from_table(alias: &str, table: &toml::Table) -> Result<Device, Box<dyn Error>> {
+        let bad = |key: &str| format!("Invalid {} for [device.{}] in config file", key, alias);
+        let string = |key: &str| -> Result<Option<String>, String> {
+            table.get(key).map(|v| v.as_str().map(|s| s.to_string()).ok_or_else(|| bad(key))).transpose()
+        };
+        let integer = |key: &str, min: i64, max: i64| -> Result<Option<i64>, String> {
+            table.get(key).map(|v| v.as_integer().filter(|n| (min..=max).contains(n)).ok_or_else(|| bad(key))).transpose()
+        };
+        if let Some(key) = table.keys().find(|k| !DEVICE_KEYS.contains(&k.as_str())) {
+            return Err(format!("Unknown key '{}' in [device.{}] in config file", key, alias).into());
+        }
+        // Only slots with a known file id, so a bad slot shows up before connecting
+        let slot = integer("slot", 1, 13)?.map(|n| n as u8);
+        if slot.is_some_and(|n| crate::face_file_id(n).is_err()) {
+            return Err(format!("Invalid slot for [device.{}] in config file, only slots 6 and 13 are known", alias).into());
+        }
+        Ok(Device {
+            alias: alias.to_string(),
+            address: string("address")?,
+            name: string("name")?,
+            slot,
+            throttle: integer("throttle", 0, 10_000)?.map(|n| Duration::from_millis(n as u64)),
+            byte_order: string("byte_order")?.map(|s| ByteOrder::parse(&s).ok_or_else(|| bad("byte_order"))).transpose()?,
+            settings: table.get("settings").map(|v| v.as_table().cloned().ok_or_else(|| bad("settings"))).transpose()?,
+        })
+    }
+}
+
+const DEVICE_KEYS: [&str; 6] = [ "address", "name", "slot", "throttle", "byte_order", "settings" ];
+
+fn default_paths() -> Vec<PathBuf> {
+    let mut paths = vec![ PathBuf::from(CONFIG_FILENAME) ];
+    if cfg!(windows) {
+        if let Some(appdata) = env::var_os("APPDATA") {
+            paths.push(PathBuf::from(appdata).join("dawfu").join(CONFIG_FILENAME));
+        }
+    } else if let Some(config_home) = env::var_os("XDG_CONFIG_HOME") {
+        paths.push(PathBuf::from(config_home).join("dawfu").join(CONFIG_FILENAME));
+    } else if let Some(home) = env::var_os("HOME") {
+        paths.push(PathBuf::from(home).join(".config").join("dawfu").join(CONFIG_FILENAME));
+    }
+    paths
+}
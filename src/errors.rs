@@ -0,0 +1,116 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Errors: codes and hints for the common failures, so they're easy to look up
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+use std::fmt;
+use crate::output;
+
+
+pub struct Entry {
+    pub code: &'static str,
+    pub summary: &'static str,
+    pub hint: &'static str,
+}
+
+//
+// The catalog
+//
+// Codes are stable, so they can be searched for in issues. E00x scanning, E01x connecting,
+// E02x transfers, E03x files given to dawfu, E04x everything else.
+//
+pub static CATALOG: [Entry; 14] = [
+    Entry { code: "E001", summary: "No Bluetooth adapters found",
+        hint: "Check Bluetooth is turned on. On Linux, check bluetoothd is running and that you're allowed to use it (e.g. in the bluetooth group)." },
+    Entry { code: "E002", summary: "Can't scan with the Bluetooth adapter",
+        hint: "The adapter may be powered off or busy. Try turning Bluetooth off and on again (on Linux: bluetoothctl power on)." },
+    Entry { code: "E003", summary: "No watch found",
+        hint: "Make sure the watch is awake and isn't connected to your phone (turn off Bluetooth on the phone). Use verbosity=1 to see the devices found. Rebranded watches may need manufacturer= or force=yes." },
+    Entry { code: "E010", summary: "Can't connect to the watch",
+        hint: "Move closer to the watch, make sure your phone isn't connected to it, and try again." },
+    Entry { code: "E011", summary: "Watch is missing a characteristic dawfu needs",
+        hint: "This may not be a MOYOUNG-V2 watch. Use verbosity=1 to list its services and characteristics." },
+    // btleplug (0.10) has no pairing or association call on any platform, so on Windows dawfu can only say how to pair by hand
+    Entry { code: "E012", summary: "Notifications never arrive",
+        hint: if cfg!(windows) {
+            "On Windows, notifications only arrive once the watch is paired with Windows: open Settings > Bluetooth & devices > Add device > Bluetooth, select the watch and wait for it to show as paired, then turn off Bluetooth on your phone and run dawfu again."
+        } else {
+            "Make sure the watch isn't connected to your phone, and try again."
+        } },
+    Entry { code: "E013", summary: "Watch stopped responding part way",
+        hint: "The watch may have gone out of range or reset. Use retries=2 to start again automatically." },
+    Entry { code: "E020", summary: "Upload didn't finish",
+        hint: "Use retries=2 to start again automatically. If it keeps stopping, try a throttle for the device in the config file." },
+    Entry { code: "E021", summary: "Watch battery is low",
+        hint: "Charge the watch first, or use lowbattery=yes to upload anyway (more slowly)." },
+    Entry { code: "E022", summary: "Unknown watch face slot",
+        hint: "Only slots 6 and 13 are known. Change slot in the device's section of the config file." },
+    Entry { code: "E030", summary: "Error in the config file",
+        hint: "Check the file against the Config file section of the README. Use config= to pick a different file." },
+    Entry { code: "E031", summary: "Error in a settings profile",
+        hint: "Run dawfu settings export to see the names and values this watch uses." },
+    Entry { code: "E040", summary: "Built without a feature",
+        hint: "Build again with --features and the feature named (see Build features in the README)." },
+    Entry { code: "E041", summary: "Timed out",
+        hint: "Allow longer with timeout=, or use verbosity=1 to see where it gets stuck." },
+];
+
+pub fn find(code: &str) -> Option<&'static Entry> {
+    CATALOG.iter().find(|e| e.code == code)
+}
+
+//
+// An error with a catalog code, to return with ?
+//
+#[derive(Debug)]
+pub struct Coded {
+    pub code: &'static str,
+    message: String,
+}
+
+impl fmt::Display for Coded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+impl Error for Coded {}
+
+pub fn coded(code: &'static str, message: impl Into<String>) -> Box<dyn Error> {
+    Box::new(Coded { code, message: message.into() })
+}
+
+fn show_hint(code: &str) {
+    if let Some(entry) = find(code) {
+        output::info(format!("Hint: {}", entry.hint));
+    }
+}
+
+//
+// Print an error that doesn't stop dawfu, with its code and hint
+//
+pub fn report(code: &'static str, message: impl fmt::Display) {
+    output::error(format!("ERROR: [{}] {}", code, message));
+    show_hint(code);
+}
+
+//
+// Print the error dawfu is stopping with, with a hint if it has a code
+//
+pub fn report_fatal(err: &(dyn Error + 'static)) {
+    output::error(format!("Error: {}", err));
+    if let Some(coded) = err.downcast_ref::<Coded>() {
+        show_hint(coded.code);
+    }
+}
+
+//
+// dawfu errors: list the catalog
+//
+pub fn list() {
+    for entry in CATALOG.iter() {
+        output::info(format!("{}  {}", entry.code, entry.summary));
+        output::info(format!("      {}", entry.hint));
+    }
+}
@@ -0,0 +1,167 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Events: things the watch tells us about on its own, like buttons pressed on it
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::process::Command;
+use crate::{media, output, protocol};
+
+
+//
+// Events we know how to decode
+//
+// The command ids are as seen by other MOYOUNG projects, and haven't been checked on many watches.
+// Use verbosity=1 to see what else the watch sends.
+//
+pub enum Event {
+    Camera,                     // Shutter pressed on the watch's camera remote
+    FindPhone,
+    Music(&'static str),        // One of MUSIC_KEYS
+}
+
+// Operation byte of CMD_SHUTTER_AND_MUSIC, in order
+pub const MUSIC_KEYS: [&str; 8] = [ "play-pause", "previous", "next", "reject-call", "volume-up", "volume-down", "play", "pause" ];
+
+// Names for on-<hook>= options
+pub const HOOKS: [&str; 3] = [ "camera", "find-phone", "music" ];
+
+pub fn decode(cmd: u8, payload: &[u8]) -> Option<Event> {
+    match (cmd, payload.first()) {
+        (protocol::CMD_FIND_PHONE, _) => Some(Event::FindPhone),
+        (protocol::CMD_SHUTTER_AND_MUSIC, None) => Some(Event::Camera),
+        (protocol::CMD_SHUTTER_AND_MUSIC, Some(op)) => MUSIC_KEYS.get(*op as usize).copied().map(Event::Music),
+        _ => None,
+    }
+}
+
+impl Event {
+    pub fn hook(&self) -> &'static str {
+        match self {
+            Event::Camera => "camera",
+            Event::FindPhone => "find-phone",
+            Event::Music(_) => "music",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Camera => write!(f, "Camera shutter pressed"),
+            Event::FindPhone => write!(f, "Find phone"),
+            Event::Music(key) => write!(f, "Music key {}", key),
+        }
+    }
+}
+
+//
+// User commands to run for events, from on-camera=, on-find-phone= and on-music=, and the find-phone-sound= to play
+//
+#[derive(Default)]
+pub struct Hooks {
+    commands: HashMap<&'static str, String>,
+    sound: Option<String>,
+}
+
+impl Hooks {
+    pub fn set(&mut self, hook: &str, command: &str) -> Result<(), Box<dyn Error>> {
+        let hook = HOOKS.iter().find(|h| **h == hook)
+            .ok_or_else(|| format!("Unknown event 'on-{}', expected one of: on-{}", hook, HOOKS.join(", on-")))?;
+        self.commands.insert(hook, command.to_string());
+        Ok(())
+    }
+
+    pub fn set_sound(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
+        if !std::path::Path::new(filename).is_file() {
+            return Err(format!("Sound file {} not found", filename).into());
+        }
+        self.sound = Some(filename.to_string());
+        Ok(())
+    }
+
+    //
+    // Run the command for an event, if there is one, without waiting for it to finish
+    //
+    // The command runs in the shell, with DAWFU_EVENT set to the hook and DAWFU_KEY to the music key.
+    // Without an on-music= command, music keys go to the desktop media player, and without an
+    // on-find-phone= command, find phone plays the find-phone-sound= if there is one.
+    //
+    pub fn run(&self, event: &Event) {
+        let command = match (self.commands.get(event.hook()), event) {
+            (Some(command), _) => command.clone(),
+            (None, Event::Music(key)) => {
+                let key = *key;
+                tokio::task::spawn_blocking(move || {
+                    if let Err(err) = media::press(key) {
+                        output::warning(format!("Unable to pass {} to the media player ({}).", key, err));
+                    }
+                });
+                return;
+            },
+            (None, Event::FindPhone) => match &self.sound {
+                Some(sound) => {
+                    let (mut player, description) = play_sound(sound);
+                    tokio::task::spawn_blocking(move || wait(&mut player, &description));
+                    return;
+                },
+                None => return,
+            },
+            (None, _) => return,
+        };
+        let mut shell = if cfg!(windows) {
+            let mut c = Command::new("cmd");
+            c.arg("/C");
+            c
+        } else {
+            let mut c = Command::new("sh");
+            c.arg("-c");
+            c
+        };
+        shell.arg(&command).env("DAWFU_EVENT", event.hook());
+        if let Event::Music(key) = event {
+            shell.env("DAWFU_KEY", key);
+        }
+        tokio::task::spawn_blocking(move || wait(&mut shell, &command));
+    }
+}
+
+//
+// Reply to an event, for the ones the watch waits on
+//
+// The watch is told we heard find phone by sending its command back. This is a guess from other
+// MOYOUNG projects; watches that don't expect it should ignore it.
+//
+pub fn reply(cmd: u8, payload: &[u8]) -> Option<Vec<u8>> {
+    match decode(cmd, payload)? {
+        Event::FindPhone => Some(protocol::build(cmd, payload)),
+        _ => None,
+    }
+}
+
+// Command to play a sound file with the system's player
+fn play_sound(filename: &str) -> (Command, String) {
+    let mut player = if cfg!(windows) {
+        let mut c = Command::new("powershell");
+        c.arg("-NoProfile").arg("-Command").arg(format!("(New-Object Media.SoundPlayer '{}').PlaySync()", filename.replace('\'', "''")));
+        c
+    } else if cfg!(target_os = "macos") {
+        Command::new("afplay")
+    } else {
+        Command::new("paplay")
+    };
+    if !cfg!(windows) {
+        player.arg(filename);
+    }
+    (player, format!("play {}", filename))
+}
+
+fn wait(command: &mut Command, description: &str) {
+    match command.status() {
+        Ok(status) if status.success() => (),
+        Ok(status) => output::warning(format!("Command '{}' failed ({}).", description, status)),
+        Err(err) => output::warning(format!("Unable to run '{}' ({}).", description, err)),
+    }
+}
@@ -0,0 +1,57 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Find watch: an example plugin, making the watch vibrate so it can be found
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+use crate::plugin::{CommandPacket, Plugin};
+
+
+// As seen by other MOYOUNG projects, and not checked on many watches
+const CMD_FIND_WATCH: u8 = 0x61;
+
+pub struct FindWatch;
+
+impl Plugin for FindWatch {
+    fn name(&self) -> &'static str {
+        "find-watch"
+    }
+
+    fn help(&self) -> &'static str {
+        "Make the watch vibrate, to find it (example plugin)."
+    }
+
+    fn encode(&self, args: &[String]) -> Result<Vec<CommandPacket>, Box<dyn Error>> {
+        if let Some(arg) = args.first() {
+            return Err(format!("find-watch doesn't take '{}'", arg).into());
+        }
+        Ok(vec![ (CMD_FIND_WATCH, Vec::new()) ])
+    }
+
+    fn decode(&self, sent: bool, cmd: u8, _payload: &[u8]) -> Option<String> {
+        (sent && cmd == CMD_FIND_WATCH).then(|| "Find watch".to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{plugin, protocol, CU_SEND};
+
+    #[test]
+    fn find_watch_is_listed() {
+        let n = plugin::find("find-watch").expect("find-watch plugin not listed");
+        assert_eq!(plugin::plugins()[n].name(), "find-watch");
+    }
+
+    #[test]
+    fn encode_and_describe() {
+        let packets = FindWatch.encode(&[]).unwrap();
+        assert_eq!(packets, vec![ (CMD_FIND_WATCH, Vec::new()) ]);
+        let data = protocol::build(packets[0].0, &packets[0].1);
+        assert_eq!(data, vec![ 0xfe, 0xea, 0x20, 0x05, 0x61 ]);
+        assert_eq!(protocol::describe(true, CU_SEND, &data), "Find watch");
+        assert!(FindWatch.encode(&[ "now".to_string() ]).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Journal: a record of successful uploads, one JSON object per line
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use crate::output;
+
+
+const JOURNAL_FILENAME: &str = "journal.jsonl";
+
+//
+// Where the journal is kept, unless journal= says otherwise
+//
+// ~/.local/share/dawfu on Linux and macOS (or $XDG_DATA_HOME/dawfu), %APPDATA%\dawfu on Windows.
+//
+pub fn default_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?)
+    } else if let Some(data_home) = env::var_os("XDG_DATA_HOME") {
+        PathBuf::from(data_home)
+    } else {
+        PathBuf::from(env::var_os("HOME")?).join(".local").join("share")
+    };
+    Some(dir.join("dawfu").join(JOURNAL_FILENAME))
+}
+
+//
+// Add an upload to the journal
+//
+pub fn record(path: &Path, entry: &Value) -> Result<(), Box<dyn Error>> {
+    let failed = |e: std::io::Error| format!("Unable to write journal {} ({})", path.display(), e);
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(failed)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(failed)?;
+    writeln!(file, "{}", entry).map_err(failed)?;
+    Ok(())
+}
+
+//
+// Show the journal, oldest first, optionally only for one device (by name or address)
+//
+pub fn show(path: &Path, name: &str, address: &str) -> Result<(), Box<dyn Error>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            output::info(format!("No uploads recorded yet ({} doesn't exist).", path.display()));
+            return Ok(());
+        },
+        Err(e) => return Err(format!("Unable to read journal {} ({})", path.display(), e).into()),
+    };
+    let mut shown = 0;
+    for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let entry: Value = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(_) => {
+                output::warning(format!("Skipping line {} of {}, it isn't a journal entry.", n + 1, path.display()));
+                continue;
+            },
+        };
+        let field = |v: &Value| v.as_str().unwrap_or("").to_string();
+        let device_name = field(&entry["device"]["name"]);
+        let device_address = field(&entry["device"]["address"]);
+        if (!name.is_empty() && device_name != name) || (!address.is_empty() && !device_address.eq_ignore_ascii_case(address)) {
+            continue;
+        }
+        let device = match entry["device"]["alias"].as_str() {
+            Some(alias) => format!("{} ({})", alias, device_address),
+            None => format!("{} ({})", device_name, device_address),
+        };
+        let place = match entry["slot"].as_u64() {
+            Some(slot) => format!("slot {}", slot),
+            None => format!("file id {}", entry["file_id"]),
+        };
+        output::info(format!("{:<19}  {}  {}  {}  crc32 {}  checksum {}",
+            field(&entry["timestamp"]).chars().take(19).collect::<String>().replace('T', " "),
+            device, field(&entry["file"]), place, entry["crc32"].as_str().unwrap_or("unknown"), field(&entry["checksum"])));
+        shown += 1;
+    }
+    if shown == 0 {
+        output::info("No matching uploads recorded.");
+    }
+    Ok(())
+}
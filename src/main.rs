@@ -4,7 +4,8 @@
 // MIT License
 
 use std::io;
-use std::io::Write;
+use std::io::{Write, Read, Seek, SeekFrom, BufReader};
+use std::fs::File;
 use std::error::Error;
 use std::time::Duration;
 use tokio::time;
@@ -24,9 +25,47 @@ use btleplug::api::{
 use btleplug::platform::{
     Manager,
     PeripheralId, Adapter,
+    Peripheral as PlatformPeripheral,
 };
+use btleplug::api::ValueNotification;
+use futures::stream::Stream;
+use std::pin::Pin;
 use std::env;
-use std::convert::TryInto;
+#[cfg(feature = "journal")]
+use std::path::PathBuf;
+use std::collections::{HashSet, VecDeque};
+use futures::stream::FuturesUnordered;
+
+mod clock;
+mod completions;
+mod config;
+mod errors;
+#[cfg(feature = "listen")]
+mod events;
+#[cfg(feature = "find-watch")]
+mod find_watch;
+#[cfg(feature = "journal")]
+mod journal;
+#[cfg(feature = "listen")]
+mod media;
+#[cfg(feature = "notify")]
+mod notify;
+mod output;
+mod plugin;
+mod protocol;
+#[cfg(feature = "scripting")]
+mod script;
+mod settings;
+#[cfg(test)]
+mod simulator;
+#[cfg(feature = "trace")]
+mod trace;
+mod traffic_log;
+#[cfg(feature = "weather")]
+mod weather;
+mod webhook;
+mod write_queue;
+use write_queue::WriteQueue;
 
 
 
@@ -42,14 +81,63 @@ const CU_SOFTREV: Uuid = uuid_from_u16(0x2a28);         // Software Revision Str
 const CU_MANUFACTURER: Uuid = uuid_from_u16(0x2a29);    // Manufacturer Name String
 
 const _SU_D0FF: Uuid = uuid::uuid!("0000d0ff-3c17-d293-8e48-14fe2e4da212");
-const _SU_FEE7: Uuid = uuid_from_u16(0xfee7);
+const SU_FEE7: Uuid = uuid_from_u16(0xfee7);
 
 const SU_FEEA: Uuid = uuid_from_u16(0xfeea);
 const CU_SEND: Uuid = uuid_from_u16(0xfee2);
 const CU_SENDFILE: Uuid = uuid_from_u16(0xfee6);
-const _CU_NOTIFYX: Uuid = uuid_from_u16(0xfee1);
+const CU_NOTIFYX: Uuid = uuid_from_u16(0xfee1);         // Health and button events, on some firmware
 const CU_NOTIFY: Uuid = uuid_from_u16(0xfee3);
 
+const MANUFACTURER: &str = "MOYOUNG-V2";               // Manufacturer Name String of compatible watches
+
+const ADVERTISED_UUIDS: [Uuid; 2] = [ SU_FEEA, SU_FEE7 ];    // Advertised services that suggest a DaFit watch
+
+//
+// File ids for CU_SENDFILE transfers
+//
+const FILE_ID_FACE: u8 = 0x74;                          // Watch face slot 13 (Watch Gallery)
+const FILE_ID_FACE_6: u8 = 0x6e;                        // Watch face slot 6 (user watch face)
+
+fn face_file_id(slot: u8) -> Result<u8, Box<dyn Error>> {
+    match slot {
+        13 => Ok(FILE_ID_FACE),
+        6 => Ok(FILE_ID_FACE_6),
+        _ => Err(errors::coded("E022", format!("Don't know the file id for watch face slot {}, only slots 6 and 13", slot))),
+    }
+}
+
+//
+// How to pace file transfers, which can be set per device in the config file
+//
+struct Transfer {
+    chunk_size: usize,          // Bytes per chunk of file data, as the watch expects (it places chunks by its own size)
+    throttle: Duration,         // Wait after each chunk
+    byte_order: Option<protocol::ByteOrder>,    // Byte order of the watch's replies, or None to work it out
+    write_type: WriteType,      // How file data is written
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Transfer { chunk_size: 244, throttle: Duration::ZERO, byte_order: None, write_type: WriteType::WithoutResponse }
+    }
+}
+
+const LOW_BATTERY: u8 = 20;                             // Below this (percent), uploads need lowbattery=yes
+
+impl Transfer {
+    // Slower and with acknowledged writes, so a watch low on battery is less likely to reset part way through.
+    // The chunk size stays the same, as the watch works out where each chunk goes from its own chunk size.
+    fn gentle(&self) -> Transfer {
+        Transfer {
+            chunk_size: self.chunk_size,
+            throttle: self.throttle.max(Duration::from_millis(50)),
+            byte_order: self.byte_order,
+            write_type: WriteType::WithResponse,
+        }
+    }
+}
+
 
 //
 // IsNotEmpty implementation
@@ -73,18 +161,120 @@ impl IsNotEmpty for &str {
     }
 }
 
-impl IsNotEmpty for Vec<u8> {
+impl<T> IsNotEmpty for Vec<T> {
     fn is_not_empty(&self) -> bool {
         !self.is_empty()
     }
 }
 
 
+//
+// Watch file data source
+//
+// Chunks are read on demand rather than loading the whole file into memory.
+//
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+pub struct FileSource {
+    reader: Box<dyn ReadSeek + Send>,
+    len: u64,
+    pos: u64,
+}
+
+impl FileSource {
+    pub fn open(filename: &str) -> io::Result<FileSource> {
+        let file = File::open(filename)?;
+        let len = file.metadata()?.len();
+        Ok(FileSource { reader: Box::new(BufReader::new(file)), len, pos: 0 })
+    }
+
+    pub fn from_vec(data: Vec<u8>) -> FileSource {
+        let len = data.len() as u64;
+        FileSource { reader: Box::new(io::Cursor::new(data)), len, pos: 0 }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Read up to len bytes starting at start. Sequential reads don't seek, so stay buffered.
+    pub fn read_chunk(&mut self, start: u64, len: usize) -> io::Result<Vec<u8>> {
+        if start >= self.len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunk is past the end of the file"));
+        }
+        let len = len.min((self.len - start) as usize);
+        if start != self.pos {
+            self.reader.seek(SeekFrom::Start(start))?;
+        }
+        let mut buf = vec![0u8; len];
+        self.reader.read_exact(&mut buf)?;
+        self.pos = start + len as u64;
+        Ok(buf)
+    }
+
+    // CRC-32 of the whole file, to tell versions apart in the journal
+    #[cfg(feature = "journal")]
+    pub fn crc32(&mut self) -> io::Result<u32> {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        self.reader.seek(SeekFrom::Start(0))?;
+        loop {
+            let n = self.reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        self.reader.seek(SeekFrom::Start(0))?;
+        self.pos = 0;
+        Ok(hasher.finalize())
+    }
+}
+
+
+type NotificationStream = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
+
 //
 // Application modes
 //
 #[derive(PartialEq)]
-enum Mode { Help, Info, Upload }
+enum Mode { Help, Info, Upload, Agps, Abort, SetTime, Sync, Weather, Notify, Settings, Trace, History, Listen, Errors, Script, Completions, Plugin(usize) }
+
+
+//
+// Which devices to accept as the watch
+//
+struct WatchFilter {
+    name: String,                   // Only a device with this name, if not empty
+    address: String,                // Only a device with this address, if not empty
+    manufacturers: Vec<String>,     // Manufacturer strings of compatible watches
+    force: bool,                    // Try devices that fail the compatibility checks
+}
+
+impl WatchFilter {
+    // Looking for one particular device
+    fn is_unique(&self) -> bool {
+        self.name.is_not_empty() || self.address.is_not_empty()
+    }
+}
+
+//
+// A compatible watch found during the scan
+//
+struct Candidate {
+    pid: PeripheralId,
+    name: String,
+    address: String,
+    rssi: Option<i16>,
+    battery_level: u8,
+}
 
 
 //
@@ -92,20 +282,19 @@ enum Mode { Help, Info, Upload }
 //
 async fn dump_services(pid: &PeripheralId, adapter: &Adapter, verbosity: u32) -> Result<(), Box<dyn Error>> {
     if verbosity > 0 {    // Display debug dump of services and readable characteristics
-        let peripheral = adapter.peripheral(&pid).await?;
+        let peripheral = adapter.peripheral(pid).await?;
         for service in peripheral.services() {
-            println!("Service {}    primary: {}", service.uuid.to_short_string(), service.primary);
+            output::debug(verbosity, format!("Service {}    primary: {}", service.uuid.to_short_string(), service.primary));
             // Print the readable chars to screen
             for characteristic in service.characteristics {
-                print!("        {}", characteristic.uuid.to_short_string());
-                println!("    {:?}", characteristic.properties);
+                output::debug(verbosity, format!("        {}    {:?}", characteristic.uuid.to_short_string(), characteristic.properties));
                 if characteristic.properties.contains(CharPropFlags::READ) {
                     let data = peripheral.read(&characteristic).await?;
-                    print!("        {}    DATA READ        ", characteristic.uuid.to_short_string());
+                    let mut line = format!("        {}    DATA READ        ", characteristic.uuid.to_short_string());
                     let mut s: String = String::new();
                     for zx in data.iter() {
                         let x = *zx;
-                        print!("{:02x} ", x);
+                        line += &format!("{:02x} ", x);
                         if x > 31 && x < 127 {
                             let c = x as char;
                             s.push(c);
@@ -113,15 +302,15 @@ async fn dump_services(pid: &PeripheralId, adapter: &Adapter, verbosity: u32) ->
                             s.push('.');
                         }
                     }
-                    print!("    '{}'", s);
+                    line += &format!("    '{}'", s);
                     if data.len() == 1 {
-                        print!("    {}", u8::from_le_bytes([data[0]]));
+                        line += &format!("    {}", u8::from_le_bytes([data[0]]));
                     } else if data.len() == 2 {
-                        print!("    {}", u16::from_le_bytes([data[0], data[1]]));
+                        line += &format!("    {}", u16::from_le_bytes([data[0], data[1]]));
                     } else if data.len() == 4 {
-                        print!("    {}", u32::from_le_bytes([data[0], data[1], data[2], data[3]]));
+                        line += &format!("    {}", u32::from_le_bytes([data[0], data[1], data[2], data[3]]));
                     }
-                    println!();
+                    output::debug(verbosity, line);
                 }
             }
         }
@@ -132,8 +321,8 @@ async fn dump_services(pid: &PeripheralId, adapter: &Adapter, verbosity: u32) ->
 //
 // Handle DeviceDiscovered events
 //
-async fn device_discovered(pid: &PeripheralId, adapter: &Adapter, wanted_name: &str, wanted_address: &str, verbosity: u32) -> Result<bool, Box<dyn Error>> {
-    let peripheral = adapter.peripheral(&pid).await?;
+async fn device_discovered(pid: &PeripheralId, adapter: &Adapter, filter: &WatchFilter, verbosity: u32) -> Result<Option<Candidate>, Box<dyn Error>> {
+    let peripheral = adapter.peripheral(pid).await?;
     let properties = peripheral.properties().await?;
     let is_connected = peripheral.is_connected().await?;
     let properties = properties.unwrap();
@@ -141,32 +330,37 @@ async fn device_discovered(pid: &PeripheralId, adapter: &Adapter, wanted_name: &
         .local_name
         .unwrap_or_else(|| String::from("(unknown)"));
     let address = properties.address.to_string();
-    print!("Found device [{}]: {}. ", address, local_name);
+    let rssi = properties.rssi;
+    // Devices are checked a few at a time, so only whole lines are printed, with the address to tell them apart
+    output::info(format!("Found device [{}]: {}.", address, local_name));
     // Check if it is the named peripheral
-    if (wanted_name.is_not_empty() && local_name != wanted_name) || (wanted_address.is_not_empty() && address != wanted_address) {
-        if verbosity > 0 {
-            println!("Skipping.");
-        } else {
-            println!();
-        }
-        return Ok(false);
+    if (filter.name.is_not_empty() && local_name != filter.name) || (filter.address.is_not_empty() && !address.eq_ignore_ascii_case(&filter.address)) {
+        output::debug(verbosity, format!("Skipping [{}].", address));
+        return Ok(None);
+    }
+
+    // Unless it was asked for by name or address (or force=yes), skip devices that advertise services but not the watch ones
+    let advertised: Vec<&Uuid> = properties.services.iter().chain(properties.service_data.keys()).collect();
+    if !filter.is_unique() && !filter.force && advertised.is_not_empty() && !advertised.iter().any(|u| ADVERTISED_UUIDS.contains(u)) {
+        output::debug(verbosity, format!("[{}] is not a watch, skipping.", address));
+        return Ok(None);
     }
 
     // possible device found
     // connect and discover services
     if !is_connected {
-        println!("Connecting... ");
+        output::info(format!("Connecting to [{}]...", address));
         if let Err(err) = peripheral.connect().await {
-            eprintln!("Error connecting to peripheral ({}).", err);
-            return Ok(false);
+            errors::report("E010", format!("Error connecting to peripheral ({}).", err));
+            return Ok(None);
         }
     }
 
     // Discover services
     peripheral.discover_services().await?;
     if verbosity > 0{
-        println!("Services on {:}...", &local_name);
-        dump_services(&pid, &adapter, verbosity).await?;
+        output::info(format!("Services on {:}...", &local_name));
+        dump_services(pid, adapter, verbosity).await?;
     }
 
       // Check that this looks like a DaFit watch
@@ -175,8 +369,11 @@ async fn device_discovered(pid: &PeripheralId, adapter: &Adapter, wanted_name: &
     let services = peripheral.services();
     let s_uuids: Vec<Uuid> = services.iter().map(|s| s.uuid).collect();
     if !(s_uuids.contains(&SU_DEVINFO) && s_uuids.contains(&SU_FEEA) && s_uuids.contains(&SU_BATTERY)) {
-        println!("This doesn't look like a compatible device.");
-        return Ok(false);
+        output::warning("This doesn't look like a compatible device.");
+        if !filter.force {
+            return Ok(None);
+        }
+        output::warning("WARNING: Carrying on anyway, as force=yes was given!");
     }
     
     // Check for all required characteristics
@@ -184,61 +381,648 @@ async fn device_discovered(pid: &PeripheralId, adapter: &Adapter, wanted_name: &
     let required_chars = vec!(CU_SOFTREV, CU_SERIALNUM, CU_MANUFACTURER, CU_BATTERY, CU_NOTIFY, CU_SEND, CU_SENDFILE);
     for rc in required_chars {
         if !chars.iter().any(|c| c.uuid==rc) {
-            println!("Device does not have all required characteristics.");
-            return Ok(false);
+            output::warning(format!("Device does not have all required characteristics ({} is missing).", rc.to_short_string()));
+            if !filter.force {
+                return Ok(None);
+            }
+            output::warning("WARNING: Carrying on anyway, as force=yes was given!");
+        }
+    }
+
+    // Read some device info, missing characteristics read as nothing
+    let read = |uuid: Uuid| {
+        let c = chars.iter().find(|c| c.uuid == uuid).cloned();
+        let peripheral = &peripheral;
+        async move {
+            match c {
+                Some(c) => peripheral.read(&c).await,
+                None => Ok(Vec::new()),
+            }
+        }
+    };
+    let software_revision = String::from_utf8_lossy(&read(CU_SOFTREV).await?).into_owned();
+    let serial_number = String::from_utf8_lossy(&read(CU_SERIALNUM).await?).into_owned();
+    let manufacturer = String::from_utf8_lossy(&read(CU_MANUFACTURER).await?).into_owned();
+    let battery_level = read(CU_BATTERY).await?.first().copied().unwrap_or(0);
+
+    output::info(format!("Device [{}]: {}\nSoftware Revision: {}\nSerial Number:     {}\nManufacturer:      {}\nBattery Level:     {}",
+        address, local_name, software_revision, serial_number, manufacturer, battery_level));
+
+    if !filter.manufacturers.contains(&manufacturer) {
+        output::warning("This doesn't look like a compatible device.");
+        if !filter.force {
+            output::info(format!("If it is a rebranded watch, try manufacturer=\"{}\".", manufacturer));
+            return Ok(None);
+        }
+        output::warning("WARNING: Carrying on anyway, as force=yes was given!");
+    }
+
+    Ok(Some(Candidate { pid: pid.clone(), name: local_name, address, rssi, battery_level }))
+}
+
+//
+// Ask the user which of several compatible watches to use
+//
+fn choose_candidate(candidates: &[Candidate]) -> Result<usize, Box<dyn Error>> {
+    output::info("More than one compatible watch found.");
+    for (n, c) in candidates.iter().enumerate() {
+        let rssi = match c.rssi {
+            Some(r) => format!("{} dBm", r),
+            None => "unknown".to_string(),
+        };
+        output::info(format!("Watch {}: {} [{}]    RSSI: {}    Battery: {}%", n, c.name, c.address, rssi, c.battery_level));
+    }
+    loop {
+        output::prompt(format!("Select watch (0-{}): ", candidates.len() - 1));
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Err("No watch selected".into());
+        }
+        match line.trim().parse::<usize>() {
+            Ok(n) if n < candidates.len() => return Ok(n),
+            _ => output::warning("Invalid selection."),
+        }
+    }
+}
+
+//
+// Scan for a compatible watch, asking the user to choose if there is more than one
+//
+async fn find_watch(adapter: &Adapter, filter: &WatchFilter, verbosity: u32) -> Result<Option<Candidate>, Box<dyn Error>> {
+    adapter
+        .start_scan(ScanFilter::default())
+        .await
+        .map_err(|e| errors::coded("E002", format!("Can't scan for devices with Bluetooth (BLE) adapter ({})", e)))?;
+    
+    // Start find device
+    let mut event_stream = adapter.events().await?;
+    let mut candidates: Vec<Candidate> = Vec::new();
+    let mut seen: HashSet<PeripheralId> = HashSet::new();
+    let unique_filter = filter.is_unique();
+
+    // Devices are checked a few at a time, so one slow device doesn't hold up the rest
+    const MAX_CHECKS: usize = 4;
+    let mut waiting: VecDeque<PeripheralId> = VecDeque::new();
+    let mut checking: HashSet<PeripheralId> = HashSet::new();
+    let mut checks = FuturesUnordered::new();
+
+    // Once a watch is found, keep scanning briefly in case there is more than one
+    const SETTLE_TIME: Duration = Duration::from_secs(5);
+    let start_instant = time::Instant::now();
+    let mut stop_instant = start_instant + Duration::new(60,0);
+    loop {
+        while checks.len() < MAX_CHECKS {
+            let pid = match waiting.pop_front() {
+                Some(pid) => pid,
+                None => break,
+            };
+            checking.insert(pid.clone());
+            checks.push(async move {
+                let result = device_discovered(&pid, adapter, filter, verbosity).await;
+                (pid, result)
+            });
+        }
+
+        let now = time::Instant::now();
+        if now > stop_instant {
+            if candidates.is_empty() {
+                output::warning("Timed out.");
+            }
+            break;
+        }
+        let step = time::timeout(stop_instant - now, async {
+            tokio::select! {
+                event = event_stream.next() => (event, None),
+                Some(checked) = checks.next(), if !checks.is_empty() => (None, Some(checked)),
+            }
+        }).await;
+        match step {
+            Ok((_, Some((pid, result)))) => {
+                checking.remove(&pid);
+                match result {
+                    Ok(Some(candidate)) => {
+                        candidates.push(candidate);
+                        if unique_filter {
+                            break;
+                        }
+                        if candidates.len() == 1 {
+                            stop_instant = stop_instant.min(time::Instant::now() + SETTLE_TIME);
+                        }
+                    },
+                    Ok(None) => (),
+                    // A device that misbehaves is skipped, it shouldn't stop the scan
+                    Err(err) => {
+                        output::warning(format!("Skipping device {:?}, it couldn't be checked ({}).", pid, err));
+                        disconnect_quietly(adapter, &pid).await;
+                    },
+                }
+            },
+            // Devices already known from an earlier scan only send updates
+            Ok((Some(CentralEvent::DeviceDiscovered(pid) | CentralEvent::DeviceUpdated(pid)), None)) if seen.insert(pid.clone()) => {
+                waiting.push_back(pid);
+            },
+            Ok((None, None)) => time::sleep(Duration::from_millis(10)).await,
+            _ => {
+                //println!("Recieved unhandled CentralEvent {:?}", event);                
+            },
+        };
+    }
+
+    // Don't leave devices connected that were still being checked
+    drop(checks);
+    for pid in checking {
+        disconnect_quietly(adapter, &pid).await;
+    }
+
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+    let chosen = if candidates.len() > 1 {
+        choose_candidate(&candidates)?
+    } else {
+        0
+    };
+
+    // Disconnect from the watches we aren't using
+    for (n, c) in candidates.iter().enumerate() {
+        if n != chosen {
+            disconnect_quietly(adapter, &c.pid).await;
+        }
+    }
+    Ok(Some(candidates.swap_remove(chosen)))
+}
+
+// Disconnect from a device we're done with, if it's connected. Errors don't matter at this point.
+async fn disconnect_quietly(adapter: &Adapter, pid: &PeripheralId) {
+    if let Ok(p) = adapter.peripheral(pid).await {
+        if p.is_connected().await.unwrap_or(false) {
+            let _ = p.disconnect().await;
+        }
+    }
+}
+
+//
+// Download a watch file from a URL
+//
+fn is_url(filename: &str) -> bool {
+    filename.starts_with("http://") || filename.starts_with("https://")
+}
+
+#[cfg(feature = "http")]
+fn download_file(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    use std::io::Read;
+    const MAX_DOWNLOAD_SIZE: u64 = 16 * 1024 * 1024;
+    output::info(format!("Downloading {}...", url));
+    let response = ureq::get(url).call()?;
+    let expected_len: Option<u64> = response.header("Content-Length").and_then(|s| s.parse().ok());
+    if let Some(len) = expected_len {
+        if len > MAX_DOWNLOAD_SIZE {
+            return Err(format!("Download is too large ({} bytes, maximum is {})", len, MAX_DOWNLOAD_SIZE).into());
+        }
+    }
+    let mut data: Vec<u8> = Vec::new();
+    response.into_reader().take(MAX_DOWNLOAD_SIZE + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_DOWNLOAD_SIZE {
+        return Err(format!("Download is too large (maximum is {} bytes)", MAX_DOWNLOAD_SIZE).into());
+    }
+    if let Some(len) = expected_len {
+        if data.len() as u64 != len {
+            return Err(format!("Download incomplete: received {} of {} bytes", data.len(), len).into());
+        }
+    }
+    if data.is_empty() {
+        return Err("Downloaded file is empty".into());
+    }
+    output::info(format!("Downloaded {} bytes.", data.len()));
+    Ok(data)
+}
+
+#[cfg(not(feature = "http"))]
+fn download_file(_url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("dawfu was built without the http feature, so can't download files".into())
+}
+
+//
+// Send a file to the watch, with the given file id
+//
+// The watch requests each chunk in turn, then reports a checksum when it has the lot.
+// Returns the checksum the watch reported, or None if the transfer didn't finish.
+//
+// Unless transfer.byte_order is set, the byte order is worked out from the chunk numbers. A file that
+// fits in one chunk only ever has chunk 0 asked for, so its checksum is read big endian, with a warning.
+//
+#[allow(clippy::too_many_arguments)]
+async fn send_file(writer: &WriteQueue, csend: &Characteristic, csendfile: &Characteristic, notification_stream: &mut NotificationStream,
+                   filedata: &mut FileSource, file_id: u8, transfer: &Transfer, verbosity: u32) -> Result<Option<u32>, Box<dyn Error>> {
+    let chunk_size = transfer.chunk_size;
+    const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+    std::io::stdout().flush().unwrap();
+
+    let start_instant = time::Instant::now();
+    let start_retries = writer.retries();
+    let mut bytes_sent: usize = 0;
+
+    // Send the prep command
+    let fsize: u32 = filedata.len() as u32;
+    let data = protocol::build(file_id, &fsize.to_be_bytes());
+    output::debug(verbosity, format!("SEND: {}", data.iter().map(|c| format!("{:02x} ", c)).collect::<String>()));
+    writer.write(csend, &data, WriteType::WithoutResponse).await?;
+
+    let mut expected_num: usize = 0;
+    let mut byte_order = transfer.byte_order.unwrap_or(protocol::ByteOrder::Big);
+    let mut byte_order_known = transfer.byte_order.is_some();
+
+    // Loop until we receive an 'all done' message
+    let mut finished: Option<u32> = None;
+    while finished.is_none() {                       
+        output::debug(verbosity, "Waiting for notification...");
+        let data = match time::timeout(NOTIFY_TIMEOUT, notification_stream.next()).await {
+            Ok(Some(x)) if x.uuid != CU_NOTIFY => {
+                // Events from the other notify characteristic aren't part of the transfer
+                traffic_log::record(false, x.uuid, &x.value);
+                output::debug(verbosity, format!("{}: {}    {}", recv_label(x.uuid), protocol::to_hex(&x.value), protocol::describe(false, x.uuid, &x.value)));
+                continue;
+            },
+            Ok(Some(x)) => {
+                traffic_log::record(false, x.uuid, &x.value);
+                x.value
+            },
+            Ok(None) => { 
+                output::error("ERROR: reading data from notification"); 
+                break;
+            },
+            Err(_) if expected_num == 0 => {
+                errors::report("E012", "No notifications received from the watch.");
+                return Err("No response from watch".into());
+            },
+            Err(_) => {
+                errors::report("E013", "Watch stopped responding");
+                break;
+            },
+        };
+
+        output::debug(verbosity, format!("RECV: {}", data.iter().map(|c| format!("{:02x} ", c)).collect::<String>()));
+
+        // Anything we don't understand is skipped, rather than giving up on the transfer
+        let reply = protocol::parse(&data).and_then(|packet| protocol::transfer_reply(&packet, file_id));
+        match reply {
+            Some(protocol::TransferReply::Complete(bytes)) => {         // All done
+                let checksum = byte_order.u32(bytes);
+                output::progress(100.0);
+                output::success(format!("All data recived by watch. Checksum: {:08x} ({})", checksum, checksum as i32));
+                if !byte_order_known && protocol::ByteOrder::Little.u32(bytes) != checksum {
+                    output::warning(format!("WARNING: The watch's byte order couldn't be worked out, so the checksum might be {:08x} instead. Set byte_order for the device in the config file to be sure.",
+                        protocol::ByteOrder::Little.u32(bytes)));
+                }
+
+                writer.write(csend, &protocol::build(file_id, &0u32.to_be_bytes()), WriteType::WithoutResponse).await?;
+                finished = Some(checksum);
+            },
+            Some(protocol::TransferReply::ChunkRequest(chunknum)) => {     // Ready for chunk
+                // Some firmware sends the chunk number the other way round. If it only makes sense backwards, switch.
+                if transfer.byte_order.is_none() && byte_order == protocol::ByteOrder::Big
+                    && protocol::ByteOrder::Big.u16(chunknum) as usize != expected_num
+                    && protocol::ByteOrder::Little.u16(chunknum) as usize == expected_num {
+                    output::warning("Watch sends chunk numbers little endian, reading them that way from now on.");
+                    byte_order = protocol::ByteOrder::Little;
+                }
+                // Chunk numbers that read differently each way round (e.g. not chunk 0) settle the byte order
+                if chunknum[0] != chunknum[1] && byte_order.u16(chunknum) as usize == expected_num {
+                    byte_order_known = true;
+                }
+                let chunknum = byte_order.u16(chunknum) as usize;
+                let startidx: usize = chunknum * chunk_size;
+                if startidx as u64 >= filedata.len() {
+                    output::warning(format!("WARNING: Watch asked for chunk {}, which is past the end of the file", chunknum));
+                    continue;
+                }
+
+                if chunknum != expected_num {
+                    output::warning(format!("WARNING: Expected request for chunk {}, got request for chunk {}", expected_num, chunknum));
+                }
+                expected_num = chunknum + 1;
+                output::debug(verbosity, format!("Sending chunk #{}", chunknum));
+                if verbosity == 0 {
+                    output::progress((chunknum * chunk_size * 100) as f64 / (fsize as f64));
+                }
+                let chunk = filedata.read_chunk(startidx as u64, chunk_size)?;
+                writer.write(csendfile, &chunk, transfer.write_type).await?;  // Send requested chunk
+                bytes_sent += chunk.len();
+                if !transfer.throttle.is_zero() {
+                    time::sleep(transfer.throttle).await;
+                }
+            },
+            None => output::warning(format!("WARNING: Unexpected data from watch, skipping: {}", protocol::to_hex(&data))),
+        }
+    }
+    if finished.is_some() {
+        output::success("File send finished!");
+    }
+    let secs = start_instant.elapsed().as_secs_f64();
+    output::info(format!("Sent {} bytes in {:.1} seconds ({:.1} KB/s), with {} write retries.",
+        bytes_sent, secs, bytes_sent as f64 / 1024.0 / secs.max(0.001), writer.retries() - start_retries));
+    Ok(finished)
+}
+
+//
+// Subscribe to notifications: CU_NOTIFY for replies, and CU_NOTIFYX if the watch has it
+//
+async fn subscribe_notifications(peripheral: &PlatformPeripheral) -> Result<NotificationStream, Box<dyn Error>> {
+    let chars = peripheral.characteristics();
+    let cnotify = chars.iter().find(|c| c.uuid == CU_NOTIFY).ok_or_else(|| errors::coded("E011", "Watch has no notify characteristic"))?;
+    peripheral.subscribe(cnotify).await?; // clippy removed &
+    if let Some(cnotifyx) = chars.iter().find(|c| c.uuid == CU_NOTIFYX) {
+        if let Err(err) = peripheral.subscribe(cnotifyx).await {
+            output::warning(format!("Unable to subscribe to {} notifications ({}).", CU_NOTIFYX.to_short_string(), err));
+        }
+    }
+    Ok(peripheral.notifications().await?)
+}
+
+// How to label a received packet, with where it came from if that's not CU_NOTIFY
+fn recv_label(uuid: Uuid) -> String {
+    if uuid == CU_NOTIFY {
+        "RECV".to_string()
+    } else {
+        format!("RECV [{}]", uuid.to_short_string())
+    }
+}
+
+//
+// A connection to the watch that can be made again, if it drops part way through a transfer
+//
+trait Connection {
+    // Reconnect if the connection has dropped, returning the new notification stream (or None if it was still up)
+    async fn reconnect(&self) -> Result<Option<NotificationStream>, Box<dyn Error>>;
+}
+
+impl Connection for PlatformPeripheral {
+    async fn reconnect(&self) -> Result<Option<NotificationStream>, Box<dyn Error>> {
+        if self.is_connected().await? {
+            return Ok(None);
+        }
+        output::info("Reconnecting...");
+        self.connect().await?;
+        self.discover_services().await?;
+        Ok(Some(subscribe_notifications(self).await?))
+    }
+}
+
+//
+// Get the watch ready to start a transfer again: reconnect if need be, and cancel the unfinished transfer
+//
+async fn restart_transfer(connection: &impl Connection, writer: &WriteQueue, csend: &Characteristic,
+                          notification_stream: &mut NotificationStream, file_id: u8) -> Result<(), Box<dyn Error>> {
+    if let Some(stream) = connection.reconnect().await? {
+        *notification_stream = stream;
+    }
+    writer.write(csend, &protocol::build(file_id, &0u32.to_be_bytes()), WriteType::WithoutResponse).await?;
+    time::sleep(Duration::from_millis(1000)).await;
+    // Throw away anything left over from the old transfer
+    while let Ok(Some(n)) = time::timeout(Duration::from_millis(200), notification_stream.next()).await {
+        traffic_log::record(false, n.uuid, &n.value);
+    }
+    Ok(())
+}
+
+//
+// Send a file, starting again up to `retries` times if it fails (retries=)
+//
+// Returns the result of the last attempt, and how many attempts there were.
+//
+#[allow(clippy::too_many_arguments)]
+async fn send_file_with_retries(connection: &impl Connection, writer: &WriteQueue, csend: &Characteristic, csendfile: &Characteristic,
+                                notification_stream: &mut NotificationStream, filedata: &mut FileSource, file_id: u8, transfer: &Transfer,
+                                retries: u32, verbosity: u32) -> (Result<Option<u32>, Box<dyn Error>>, u32) {
+    let mut attempts: u32 = 1;
+    loop {
+        let result = send_file(writer, csend, csendfile, notification_stream, filedata, file_id, transfer, verbosity).await;
+        if matches!(result, Ok(Some(_))) || attempts > retries {
+            return (result, attempts);
+        }
+        let reason = match &result {
+            Ok(_) => "transfer did not finish".to_string(),
+            Err(err) => err.to_string(),
+        };
+        output::warning(format!("Upload failed ({}), starting again (retry {} of {})...", reason, attempts, retries));
+        attempts += 1;
+        if let Err(err) = restart_transfer(connection, writer, csend, notification_stream, file_id).await {
+            return (Err(err), attempts);
         }
     }
+}
 
-    // Read some device info
-    let mut c: &Characteristic;
-    let mut data: Vec<u8>;
+//
+// Set the watch time, in the given timezone (or the local timezone)
+//
+async fn set_time(writer: &WriteQueue, csend: &Characteristic, clock_correction: chrono::TimeDelta, tz: Option<chrono::FixedOffset>) -> Result<(), Box<dyn Error>> {
+    let now = clock::next_second(clock_correction).await;
+    let offset = tz.unwrap_or_else(|| clock::local_offset(now));
+    output::info(format!("Setting time to {}...", now.with_timezone(&offset).format("%Y-%m-%d %H:%M:%S %:z")));
+    writer.write(csend, &protocol::build(protocol::CMD_SYNC_TIME, &clock::time_payload(now, offset)), WriteType::WithoutResponse).await?;
+    Ok(())
+}
 
-    c = chars.iter().find(|c| c.uuid == CU_SOFTREV).unwrap();
-    data = peripheral.read(c).await?;                    
-    let software_revision = String::from_utf8_lossy(&data).into_owned();
+//
+// Ask the watch for a setting. Returns None if the watch doesn't answer.
+//
+async fn query_setting(writer: &WriteQueue, csend: &Characteristic, notification_stream: &mut NotificationStream,
+                       setting: &settings::Setting, verbosity: u32) -> Result<Option<toml::Value>, Box<dyn Error>> {
+    writer.write(csend, &protocol::build(setting.query, &[]), WriteType::WithoutResponse).await?;
+    while let Ok(Some(n)) = time::timeout(Duration::from_secs(2), notification_stream.next()).await {
+        traffic_log::record(false, n.uuid, &n.value);
+        output::debug(verbosity, format!("{}: {}", recv_label(n.uuid), protocol::to_hex(&n.value)));
+        if let Some(packet) = protocol::parse(&n.value).filter(|p| n.uuid == CU_NOTIFY && p.cmd == setting.query) {
+            return Ok(setting.decode(packet.payload));
+        }
+    }
+    Ok(None)
+}
 
-    c = chars.iter().find(|c| c.uuid == CU_SERIALNUM).unwrap();
-    data = peripheral.read(c).await?;
-    let serial_number = String::from_utf8_lossy(&data).into_owned();
+async fn query_settings(writer: &WriteQueue, csend: &Characteristic, notification_stream: &mut NotificationStream, verbosity: u32)
+                        -> Result<Vec<(&'static settings::Setting, Option<toml::Value>)>, Box<dyn Error>> {
+    let mut values = Vec::new();
+    for setting in settings::SETTINGS.iter() {
+        values.push((setting, query_setting(writer, csend, notification_stream, setting, verbosity).await?));
+    }
+    // Every setting unsupported is much more likely to be notifications not arriving
+    if values.iter().all(|(_, v)| v.is_none()) {
+        return Err(errors::coded("E012", "The watch didn't answer any of the settings queries"));
+    }
+    Ok(values)
+}
 
-    c = chars.iter().find(|c| c.uuid == CU_MANUFACTURER).unwrap();
-    data = peripheral.read(c).await?;
-    let manufacturer = String::from_utf8_lossy(&data).into_owned();
+// Pulling steps and sleep isn't one of them: dawfu knows no command to ask for the history, and has no local store to keep it in
+const SYNC_ACTIONS: [&str; 2] = [ "time", "weather" ];
 
-    c = chars.iter().find(|c| c.uuid == CU_BATTERY).unwrap();
-    data = peripheral.read(c).await?;
-    let battery_level = format!("{}", data[0]);
+//
+// Find the watch and sync it. Returns the address of the watch, or None if it wasn't found.
+//
+async fn sync_once(adapter: &Adapter, filter: &WatchFilter, actions: &[String], clock_correction: chrono::TimeDelta,
+                   tz: Option<chrono::FixedOffset>, weather_payload: &[u8], verbosity: u32) -> Result<Option<String>, Box<dyn Error>> {
+    let watch = match find_watch(adapter, filter, verbosity).await? {
+        Some(w) => w,
+        None => {
+            errors::report("E003", "Unable to find a watch.");
+            return Ok(None);
+        },
+    };
+    let peripheral = adapter.peripheral(&watch.pid).await?;
+    let result = sync_watch(&peripheral, actions, clock_correction, tz, weather_payload).await;
+    if peripheral.is_connected().await? {
+        output::info("Disconnecting.");
+        peripheral.disconnect().await?;
+    }
+    result.map(|_| Some(watch.address))
+}
 
-    println!("Software Revision: {}", software_revision);
-    println!("Serial Number:     {}", serial_number);
-    println!("Manufacturer:      {}", manufacturer);
-    println!("Battery Level:     {}", battery_level);
+//
+// Wait for the bluetooth adapter to be available, e.g. after a USB dongle is plugged back in
+//
+async fn wait_for_adapter(manager: &Manager, idx: usize) -> Adapter {
+    let mut waiting = false;
+    loop {
+        if let Ok(adapters) = manager.adapters().await {
+            if let Some(adapter) = adapters.get(idx) {
+                if adapter.adapter_info().await.is_ok() {
+                    if waiting {
+                        output::success("Bluetooth adapter is back.");
+                    }
+                    return adapter.clone();
+                }
+            }
+        }
+        if !waiting {
+            output::warning("Waiting for Bluetooth adapter...");
+            waiting = true;
+        }
+        time::sleep(Duration::from_secs(5)).await;
+    }
+}
 
-    if manufacturer != "MOYOUNG-V2" {
-        println!("This doesn't look like a compatible device.");
-        return Ok(false);
+//
+// Sync the watch: perform each of the sync actions
+//
+async fn sync_watch(peripheral: &PlatformPeripheral, actions: &[String], clock_correction: chrono::TimeDelta, tz: Option<chrono::FixedOffset>,
+                    weather_payload: &[u8]) -> Result<(), Box<dyn Error>> {
+    let chars = peripheral.characteristics();
+    let csend = chars.iter().find(|c| c.uuid == CU_SEND).ok_or_else(|| errors::coded("E011", "Watch has no send characteristic"))?;
+    let writer = WriteQueue::new(peripheral.clone());
+    for action in actions {
+        match action.as_str() {
+            "time" => set_time(&writer, csend, clock_correction, tz).await?,
+            "weather" => {
+                output::info("Sending weather...");
+                writer.write(csend, &protocol::build(protocol::CMD_SET_WEATHER_TODAY, weather_payload), WriteType::WithoutResponse).await?;
+            },
+            _ => (),
+        }
     }
+    time::sleep(Duration::from_millis(1000)).await;
+    Ok(())
+}
 
-    Ok(true)
+//
+// The cargo feature a mode needs, if it was left out of this build
+//
+fn missing_feature(mode: &Mode) -> Option<&'static str> {
+    let (feature, built) = match mode {
+        Mode::Weather => ("weather", cfg!(feature = "weather")),
+        Mode::Notify => ("notify", cfg!(feature = "notify")),
+        Mode::Trace => ("trace", cfg!(feature = "trace")),
+        Mode::History => ("journal", cfg!(feature = "journal")),
+        Mode::Listen => ("listen", cfg!(feature = "listen")),
+        Mode::Script => ("scripting", cfg!(feature = "scripting")),
+        _ => return None,
+    };
+    if built { None } else { Some(feature) }
+}
+
+// A file id, in decimal or hex (e.g. 116 or 0x74)
+fn parse_file_id(s: &str) -> Result<u8, Box<dyn Error>> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse::<u8>(),
+    };
+    parsed.map_err(|_| format!("Invalid file id '{}', expected 0 to 255 (or 0x00 to 0xff)", s).into())
+}
+
+fn parse_yes_no(s: &str) -> Result<bool, Box<dyn Error>> {
+    match s {
+        "yes" | "true" | "1" => Ok(true),
+        "no" | "false" | "0" => Ok(false),
+        _ => Err(format!("Invalid value '{}', expected yes or no", s).into()),
+    }
+}
+
+const TIMEOUT_EXIT_CODE: i32 = 124;                    // Same as timeout(1)
+
+//
+// Parse a duration such as 90s, 30m or 2h (plain numbers are seconds)
+//
+fn parse_duration(s: &str) -> Result<Duration, Box<dyn Error>> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => (&s[..idx], &s[idx..]),
+        None => (s, "s"),
+    };
+    let n: u64 = num.parse().map_err(|_| format!("Invalid duration '{}'", s))?;
+    let secs = match unit {
+        "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        _ => return Err(format!("Invalid duration '{}', expected e.g. 30m", s).into()),
+    };
+    Ok(Duration::from_secs(secs))
 }
 
 //
 // Main function
 //
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() {
+    if let Err(err) = run().await {
+        errors::report_fatal(&*err);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
 
-    println!("dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches");
+    output::set_color("auto")?;
     let mut device_name: String = "".to_string();
     let mut device_address: String = "".to_string();
     let mut filename: String = "".to_string();
     let mut verbosity: u32 = 0;    
     let mut selected_adapter: Option<usize> = None;
+    let mut file_id: Option<u8> = None;
+    let mut positional: Vec<String> = Vec::new();
+    let mut tz: Option<chrono::FixedOffset> = None;
+    let mut ntp_server: String = "".to_string();
+    let mut sync_every: Duration = Duration::from_secs(30 * 60);
+    let mut sync_actions: Vec<String> = vec![ "time".to_string() ];
+    let mut config_file: Option<String> = None;
+    #[cfg(feature = "weather")]
+    let mut condition: String = "".to_string();
+    #[cfg(feature = "weather")]
+    let mut temperature: Option<f64> = None;
+    #[cfg(feature = "weather")]
+    let mut city: String = "".to_string();
+
+    let mut device_alias: Option<String> = None;
+    let mut upload_retries: u32 = 0;
+    let mut overall_timeout: Option<Duration> = None;
+    let mut extra_manufacturer: Option<String> = None;
+    let mut force_compatible: bool = false;
+    let mut low_battery_ok: bool = false;
+    #[cfg(feature = "journal")]
+    let mut journal_file: Option<PathBuf> = journal::default_path();
+    #[cfg(feature = "listen")]
+    let mut hooks = events::Hooks::default();
 
-    let mut _slot: u32 = 13;      // From 1 to 13, counting the watch faces on the watch and in DaFit app. 13 is the Watch Gallery, 6 is the user watch face.
-                                 // When setting watch face, we use 0x0D (13). When specifying slot, we use 0x74 (decimal 116) which is 103d + 13d.
-                                 // When setting watch face 6, we use file 0x6E (decimal 110), which is 104d + 6d.
+    // Watch face slot, from 1 to 13, counting the watch faces on the watch and in DaFit app. 13 is the Watch Gallery, 6 is the user watch face.
+    // When setting watch face, we use 0x0D (13). When specifying slot, we use 0x74 (decimal 116) which is 103d + 13d.
+    // When setting watch face 6, we use file 0x6E (decimal 110), which is 104d + 6d.
 
     // process command-line arguments
     let args: Vec<String> = env::args().collect();
@@ -248,11 +1032,47 @@ async fn main() -> Result<(), Box<dyn Error>> {
         match &args[1][..] {
             "info" => Mode::Info,
             "upload" => Mode::Upload,
-            _ => Mode::Help,
+            "agps" => Mode::Agps,
+            "abort" => Mode::Abort,
+            "set-time" => Mode::SetTime,
+            "sync" => Mode::Sync,
+            "weather" => Mode::Weather,
+            "notify" => Mode::Notify,
+            "settings" => Mode::Settings,
+            "trace" => Mode::Trace,
+            "history" => Mode::History,
+            "listen" => Mode::Listen,
+            "errors" => Mode::Errors,
+            "script" => Mode::Script,
+            "completions" => Mode::Completions,
+            name => match plugin::find(name) {
+                Some(n) => Mode::Plugin(n),
+                None => Mode::Help,
+            },
         }
     };
 
-    for arg in args.iter().skip(2) {
+    if let Some(feature) = missing_feature(&mode) {
+        return Err(errors::coded("E040", format!("dawfu was built without the {} feature, so can't do {}", feature, args[1])));
+    }
+
+    // Completion scripts are for the shell to read, so don't print anything else
+    if mode == Mode::Completions {
+        print!("{}", completions::script(args.get(2).map(|s| s.as_str()).unwrap_or(""))?);
+        return Ok(());
+    }
+
+    println!("dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches");
+
+    // Some modes take an action, e.g. agps push
+    let mut action: String = "".to_string();
+    let mut skip = 2;
+    if (mode == Mode::Agps || mode == Mode::Notify || mode == Mode::Settings || mode == Mode::Trace) && args.len() > 2 && !args[2].contains('=') {
+        action = args[2].clone();
+        skip = 3;
+    }
+
+    for arg in args.iter().skip(skip) {
         if arg.contains('=') {
             let idx = arg.find('=').unwrap();
             let lhs = (arg[0..idx]).to_string();
@@ -262,30 +1082,242 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 "address"   => device_address   = rhs,
                 "verbosity" => verbosity        = rhs.parse::<u32>().unwrap(),
                 "adapter"   => selected_adapter = Some(rhs.parse::<usize>().unwrap()),
-                _           => filename         = arg.clone(),
+                "fileid"    => file_id          = Some(parse_file_id(&rhs)?),
+                "trafficlog" => traffic_log::open(&rhs)?,
+                "color"     => output::set_color(&rhs)?,
+                "logfile"   => output::open_log(&rhs)?,
+                "tz"        => tz               = Some(clock::parse_tz(&rhs)?),
+                "ntp"       => ntp_server       = rhs,
+                "every"     => sync_every       = parse_duration(&rhs)?,
+                "actions"   => sync_actions     = rhs.split(',').map(|a| a.trim().to_string()).collect(),
+                "config"    => config_file      = Some(rhs),
+                #[cfg(feature = "weather")]
+                "condition" => condition        = rhs,
+                #[cfg(feature = "weather")]
+                "temp"      => temperature      = Some(rhs.parse::<f64>().map_err(|_| format!("Invalid temperature '{}'", rhs))?),
+                #[cfg(feature = "weather")]
+                "city"      => city             = rhs,
+                "device"    => device_alias     = Some(rhs),
+                "webhook"   => webhook::set_url(&rhs)?,
+                "manufacturer" => extra_manufacturer = Some(rhs),
+                "force"     => force_compatible = parse_yes_no(&rhs)?,
+                "lowbattery" => low_battery_ok  = parse_yes_no(&rhs)?,
+                "timeout"   => overall_timeout  = Some(parse_duration(&rhs)?),
+                #[cfg(feature = "journal")]
+                "journal"   => journal_file     = Some(PathBuf::from(rhs)),
+                "retries"   => upload_retries   = rhs.parse::<u32>().map_err(|_| format!("Invalid number of retries '{}'", rhs))?,
+                #[cfg(feature = "listen")]
+                "find-phone-sound" => hooks.set_sound(&rhs)?,
+                #[cfg(feature = "listen")]
+                hook if hook.starts_with("on-") => hooks.set(&hook[3..], &rhs)?,
+                _           => {
+                    filename = arg.clone();
+                    positional.push(arg.clone());
+                },
             };
         } else {
             filename = arg.clone();
+            positional.push(arg.clone());
         }
     }
 
+    if mode == Mode::Agps && (action != "push" || file_id.is_none() || filename.is_empty()) {
+        println!("usage: dawfu agps push fileid=N [options] filename");
+        output::info("The AGPS file id varies between watch models, so it must be given with fileid=N.");
+        return Ok(());
+    }
+
+    if mode == Mode::Notify && action != "test" {
+        println!("usage: dawfu notify test [options]");
+        return Ok(());
+    }
+
+    if mode == Mode::Settings && !(action == "show" || action == "apply" || (action == "export" && filename.is_not_empty())) {
+        println!("usage: dawfu settings show [options]");
+        println!("       dawfu settings apply [options] [profile.toml]");
+        println!("       dawfu settings export [options] profile.toml");
+        return Ok(());
+    }
+
+    if mode == Mode::Trace && (action != "diff" || positional.len() != 2) {
+        println!("usage: dawfu trace diff ours.jsonl theirs.btsnoop");
+        output::info("Either file can be a traffic log (from trafficlog=) or a btsnoop capture (e.g. Android's btsnoop_hci.log).");
+        return Ok(());
+    }
+
     if mode == Mode::Help {
         println!("usage: dawfu mode [options] [filename]");
         println!("mode:        info                        Show device information.");
         println!("             upload                      Upload a binary watch file.");
+        println!("             agps push                   Upload AGPS (ephemeris) data.");
+        println!("             abort                       Cancel a stuck file transfer.");
+        println!("             set-time                    Set the watch to the current local time.");
+        println!("             sync                        Keep reconnecting on a schedule to sync the watch.");
+        println!("             weather                     Send today's weather to the watch.");
+        println!("             notify test                 Send a sample notification of each type.");
+        println!("             settings show               Show the settings on the watch.");
+        println!("             settings apply              Apply the settings in a profile to the watch.");
+        println!("             settings export             Save the settings on the watch to a profile.");
+        println!("             trace diff                  Compare a traffic log with a capture of the official app.");
+        println!("             history                     Show the uploads recorded in the journal.");
+        println!("             listen                      Stay connected and run commands for buttons pressed on the watch.");
+        println!("             script                      Run a rhai script against the watch.");
+        println!("             errors                      List error codes, with hints for fixing them.");
+        println!("             completions bash            Print a shell completion script (bash, zsh, fish, powershell).");
+        for p in plugin::plugins() {
+            println!("             {:<27} {}", p.name(), p.help());
+        }
         println!("             help                        Show this help information.");
         println!("options:     name=MyWatch                Limit to devices with matching name.");
         println!("             address=01:23:45:67:89:ab   Limit to devices with matching address.");
         println!("             verbosity=1                 Set debug message verbosity.");
         println!("             adapter=1                   Select which bluetooth adapter to use.");
-        println!("filename:                                File to upload.");
+        println!("             fileid=N                    File id to send AGPS data to (or abort), e.g. 0x74.");
+        println!("             trafficlog=file.jsonl       Log all packets sent and received to a file.");
+        println!("             color=auto                  Colour output: auto, always or never.");
+        println!("             logfile=dawfu.log           Log full debug output to a file.");
+        println!("             tz=+10:00                   Timezone for set-time (default: system timezone).");
+        println!("             ntp=pool.ntp.org            Get the time for set-time from an NTP server.");
+        println!("             every=30m                   How often to sync.");
+        println!("             actions=time                What to do each sync (time, weather).");
+        println!("             condition=rain              Weather condition, or OpenWeatherMap condition code.");
+        println!("             temp=21                     Temperature for weather, in degrees Celsius.");
+        println!("             city=Sydney                 City name for weather.");
+        println!("             config=dawfu.toml           Config file to use (default: dawfu.toml).");
+        println!("             device=alias                Use a [device.alias] section of the config file.");
+        println!("             webhook=URL                 POST JSON to a URL when uploads start, finish or fail.");
+        println!("             retries=2                   Start a failed upload again, up to this many times.");
+        println!("             timeout=5m                  Give up if everything hasn't finished in this time.");
+        println!("             manufacturer=NAME           Also accept watches reporting this manufacturer.");
+        println!("             force=yes                   Try devices that fail the compatibility checks.");
+        println!("             lowbattery=yes              Upload even if the watch battery is low (more slowly).");
+        println!("             on-find-phone=COMMAND       Command to run for a watch event in listen mode (camera, find-phone, music).");
+        println!("             find-phone-sound=ring.wav   Sound to play for find phone in listen mode.");
+        println!("             journal=file.jsonl          Record successful uploads in this file (default: in the user data directory).");
+        println!("filename:                                File to upload (or http/https URL), or script to run.");
         println!();
         return Ok(());
     }
 
-    let mut filedata: Vec::<u8> = Vec::new();
-    if filename.is_not_empty() && mode == Mode::Upload { // open the file, read the whole lot to memory
-        filedata = std::fs::read(filename)?;
+    if mode == Mode::Script && filename.is_empty() {
+        println!("usage: dawfu script [options] filename");
+        return Ok(());
+    }
+
+    if mode == Mode::Errors {
+        errors::list();
+        return Ok(());
+    }
+
+    // Comparing traces doesn't need the watch
+    #[cfg(feature = "trace")]
+    if mode == Mode::Trace {
+        return trace::diff(&positional[0], &positional[1]);
+    }
+
+    // Whatever state the Bluetooth stack gets into, don't hang forever
+    if let Some(limit) = overall_timeout {
+        tokio::spawn(async move {
+            time::sleep(limit).await;
+            errors::report("E041", format!("Timed out after {} seconds.", limit.as_secs()));
+            std::process::exit(TIMEOUT_EXIT_CODE);
+        });
+    }
+
+    let config = config::Config::load(config_file.as_deref())?;
+
+    // Per-device profile, picked by alias or address
+    let mut device = match &device_alias {
+        Some(alias) => Some(config.device(alias)?),
+        None if device_address.is_not_empty() => config.device_with_address(&device_address)?,
+        None => None,
+    };
+    if let Some(d) = &device {
+        if device_address.is_empty() {
+            device_address = d.address.clone().unwrap_or_default();
+        }
+        if device_name.is_empty() {
+            device_name = d.name.clone().unwrap_or_default();
+        }
+    }
+
+    // The journal doesn't need the watch either
+    #[cfg(feature = "journal")]
+    if mode == Mode::History {
+        let path = journal_file.ok_or("Unable to work out where the journal is, give it with journal=")?;
+        return journal::show(&path, &device_name, &device_address);
+    }
+
+    // Rebranded watches report other manufacturers, which can be allowed in the config file or with manufacturer=
+    let mut manufacturers = vec![ MANUFACTURER.to_string() ];
+    manufacturers.extend(config.manufacturers()?);
+    manufacturers.extend(extra_manufacturer);
+    let mut filter = WatchFilter { name: device_name, address: device_address, manufacturers, force: force_compatible };
+    if force_compatible {
+        output::warning("WARNING: force=yes given, so devices that don't look compatible will be tried. This might not end well!");
+    }
+
+    if mode == Mode::Sync {
+        if let Some(a) = sync_actions.iter().find(|a| !SYNC_ACTIONS.contains(&a.as_str())) {
+            return Err(format!("Unknown sync action '{}'", a).into());
+        }
+        if !cfg!(feature = "weather") && sync_actions.iter().any(|a| a == "weather") {
+            return Err(errors::coded("E040", "dawfu was built without the weather feature, so can't sync the weather"));
+        }
+    }
+
+    // Work out the weather before connecting, so any mistakes show up straight away
+    #[cfg(feature = "weather")]
+    let weather_payload = if mode == Mode::Weather || (mode == Mode::Sync && sync_actions.iter().any(|a| a == "weather")) {
+        if condition.is_empty() || temperature.is_none() {
+            if mode == Mode::Sync {
+                return Err("Syncing the weather needs condition= and temp=".into());
+            }
+            println!("usage: dawfu weather condition=rain temp=21 [city=Sydney] [options]");
+            return Ok(());
+        }
+        let icon = weather::condition_icon(&config, &condition)?;
+        let temperature = temperature.unwrap().round().clamp(-128.0, 127.0) as i8;
+        output::info(format!("Weather: {} ({}), {}°C", condition, weather::icon_name(icon), temperature));
+        weather::today_payload(icon, temperature, &city)
+    } else {
+        Vec::new()
+    };
+    #[cfg(not(feature = "weather"))]
+    let weather_payload: Vec<u8> = Vec::new();
+
+    // Check the whole profile before connecting, so the watch isn't left half set up
+    let mut profile = Vec::new();
+    if mode == Mode::Settings && action == "apply" {
+        profile = if filename.is_not_empty() {
+            settings::load_profile(&filename)?
+        } else {
+            let table = device.as_ref().and_then(|d| d.settings.as_ref())
+                .ok_or("No profile given, and no settings for this device in the config file")?;
+            settings::profile_commands(table)?
+        };
+    }
+
+    // Find out how far off the system clock is, before we start on the watch
+    let mut clock_correction = chrono::TimeDelta::zero();
+    if (mode == Mode::SetTime || mode == Mode::Sync) && ntp_server.is_not_empty() {
+        output::info(format!("Getting time from {}...", ntp_server));
+        clock_correction = clock::ntp_offset(&ntp_server).await?;
+        output::info(format!("System clock is off by {:.3} seconds.", clock_correction.num_milliseconds() as f64 / 1000.0));
+    }
+
+    let mut filedata: Option<FileSource> = None;
+    if filename.is_not_empty() && (mode == Mode::Upload || mode == Mode::Agps) { // open the file, chunks are read as the watch requests them
+        let source = if is_url(&filename) {
+            let url = filename.clone();
+            FileSource::from_vec(tokio::task::spawn_blocking(move || download_file(&url).map_err(|e| e.to_string())).await??)
+        } else {
+            FileSource::open(&filename)?
+        };
+        if source.len() > u32::MAX as u64 {
+            return Err("File is too large to send to the watch".into());
+        }
+        filedata = Some(source).filter(|s| !s.is_empty());
         // calculate quick checksum.
         // I don't actually know what they use for checksum!
         //let mut sum: i32 = 0;
@@ -297,152 +1329,270 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let manager = Manager::new().await?;
     let adapter_list = manager.adapters().await?;
-    if adapter_list.is_empty() {
-        eprintln!("No Bluetooth adapters found");
+    if adapter_list.is_empty() && mode != Mode::Sync {
+        return Err(errors::coded("E001", "No Bluetooth adapters found"));
     }
 
 
     if adapter_list.len() > 1 {
-        println!("More than one bluetooth adapter found.");
+        output::warning("More than one bluetooth adapter found.");
         for (n,adapter) in adapter_list.iter().enumerate() {
-            println!("Adapter {}: {}", n, adapter.adapter_info().await?);
+            output::info(format!("Adapter {}: {}", n, adapter.adapter_info().await?));
         }
-        if selected_adapter == None {
-            println!("Defaulting to the first adapter. Select adapter with adapter=N argument.");
+        if selected_adapter.is_none() {
+            output::info("Defaulting to the first adapter. Select adapter with adapter=N argument.");
             selected_adapter = Some(0);
         }
     } else {
         selected_adapter = Some(0);
     }
 
-    let adapter = &adapter_list[selected_adapter.unwrap()];
-    println!("Starting Bluetooth (BLE) scan using adapter {}...", adapter.adapter_info().await?);
-    adapter
-        .start_scan(ScanFilter::default())
-        .await
-        .expect("Can't scan for connected devices with Bluetooth (BLE) adapter!");
-    
-    // Start find device
-    let mut event_stream = adapter.events().await?;
-    let mut watch_device: Option<PeripheralId> = None;
-
-    let start_instant = std::time::Instant::now();
-    let stop_instant = start_instant + Duration::new(60,0);
-    loop {
-        if std::time::Instant::now() > stop_instant {
-            println!("Timed out.");
-            break;
-        }
-        let event = event_stream.next().await;
-        if event.is_none() {
-            time::sleep(Duration::from_millis(10)).await;
-            continue;
-        }
-        let event = event.unwrap();
-        match event {
-            CentralEvent::DeviceDiscovered(pid) => {
-                if device_discovered(&pid, &adapter, &device_name, &device_address, verbosity).await? {
-                    watch_device = Some(pid);
-                    break;
+    // Sync runs until it's killed
+    if mode == Mode::Sync {
+        let adapter_idx = selected_adapter.unwrap();
+        let mut adapter = wait_for_adapter(&manager, adapter_idx).await;
+        output::info(format!("Starting Bluetooth (BLE) scan using adapter {}...", adapter.adapter_info().await?));
+        loop {
+            if ntp_server.is_not_empty() {
+                match clock::ntp_offset(&ntp_server).await {
+                    Ok(correction) => clock_correction = correction,
+                    Err(err) => output::error(format!("Error getting time from {} ({}).", ntp_server, err)),
                 }
-            },
-            _ => {
-                //println!("Recieved unhandled CentralEvent {:?}", event);                
-            },
-        };
+            }
+            match sync_once(&adapter, &filter, &sync_actions, clock_correction, tz, &weather_payload, verbosity).await {
+                Ok(Some(address)) => filter.address = address,      // Stick with this watch from now on
+                Ok(None) => output::warning("Will try again later."),
+                Err(err) => {
+                    output::error(format!("Error syncing watch ({}).", err));
+                    // If the adapter has gone, wait for it to come back then carry on straight away
+                    if adapter.adapter_info().await.is_err() {
+                        adapter = wait_for_adapter(&manager, adapter_idx).await;
+                        continue;
+                    }
+                },
+            }
+            output::info(format!("Next sync at {}.", (chrono::Local::now() + sync_every).format("%H:%M:%S")));
+            time::sleep(sync_every).await;
+            if adapter.adapter_info().await.is_err() {
+                adapter = wait_for_adapter(&manager, adapter_idx).await;
+            }
+        }
     }
 
-    if watch_device.is_none() {
-        println!("Unable to find a watch.");
-        return Ok(());
+    let adapter = &adapter_list[selected_adapter.unwrap()];
+    output::info(format!("Starting Bluetooth (BLE) scan using adapter {}...", adapter.adapter_info().await?));
+
+    let watch = find_watch(adapter, &filter, verbosity).await?.ok_or_else(|| errors::coded("E003", "Unable to find a watch"))?;
+    if device.is_none() {
+        device = config.device_with_address(&watch.address)?;
     }
-    let peripheral = adapter.peripheral(&watch_device.unwrap()).await?;
+    if let Some(d) = &device {
+        output::info(format!("Using [device.{}] from the config file.", d.alias));
+    }
+    let slot = device.as_ref().and_then(|d| d.slot).unwrap_or(13);
+    let defaults = Transfer::default();
+    let mut transfer = Transfer {
+        chunk_size: defaults.chunk_size,
+        throttle: device.as_ref().and_then(|d| d.throttle).unwrap_or(defaults.throttle),
+        byte_order: device.as_ref().and_then(|d| d.byte_order).or(defaults.byte_order),
+        write_type: defaults.write_type,
+    };
+    // A battery level of 0 means it couldn't be read
+    if filedata.is_some() && watch.battery_level > 0 && watch.battery_level < LOW_BATTERY {
+        if !low_battery_ok {
+            return Err(errors::coded("E021", format!("Watch battery is at {}%, charge it before uploading (or use lowbattery=yes)", watch.battery_level)));
+        }
+        output::warning(format!("WARNING: Watch battery is at {}%, sending slowly to make a reset less likely.", watch.battery_level));
+        transfer = transfer.gentle();
+    }
+    let peripheral = adapter.peripheral(&watch.pid).await?;
     let chars = peripheral.characteristics();        
     
     // Subscribe to notifications
-    let cnotify = chars.iter().find(|c| c.uuid == CU_NOTIFY).unwrap();
-    peripheral.subscribe(cnotify).await?; // clippy removed &
-    let mut notification_stream = peripheral.notifications().await?;
+    let mut notification_stream = subscribe_notifications(&peripheral).await?;
     
-    let csend = chars.iter().find(|c| c.uuid == CU_SEND).unwrap();
-    let csendfile = chars.iter().find(|c| c.uuid == CU_SENDFILE).unwrap();
+    let csend = chars.iter().find(|c| c.uuid == CU_SEND).ok_or_else(|| errors::coded("E011", "Watch has no send characteristic"))?;
+    let csendfile = chars.iter().find(|c| c.uuid == CU_SENDFILE).ok_or_else(|| errors::coded("E011", "Watch has no send file characteristic"))?;
+    let writer = WriteQueue::new(peripheral.clone());
 
     // If we have filedata, send it
-    if filedata.is_not_empty() && mode == Mode::Upload {             
-        const CHUNKSIZE: usize = 244;
-        println!("Sending watch face...");
-        std::io::stdout().flush().unwrap();
-
-        // Send the prep command
-        let mut data = vec![ 0xfe, 0xea, 0x20, 0x09, 0x74 ];
-        let fsize: u32 = filedata.len() as u32;
-        data.extend_from_slice(&fsize.to_be_bytes());
-        if verbosity > 0 {
-            println!("SEND: {}", data.iter().map(|c| format!("{:02x} ", c)).collect::<String>());
-        }
-        peripheral.write(csend, &data, WriteType::WithoutResponse).await?;
-
-        let mut expected_num: usize = 0;
-
-        // Loop until we receive an 'all done' message
-        let mut finished: bool = false;
-        while !finished {                       
-            if verbosity > 0 {
-                println!("Waiting for notification...");
-            }
-            let data = match notification_stream.next().await {
-                Some(x) => x.value,
-                _ => { 
-                    println!("ERROR: reading data from notification"); 
-                    break;
+    if let Some(filedata) = filedata.as_mut() {
+        let (what, send_id) = if mode == Mode::Upload { ("watch face", face_file_id(slot)?) } else { ("AGPS data", file_id.unwrap()) };
+        let event = serde_json::json!({
+            "device": { "name": watch.name, "address": watch.address },
+            "file": filename,
+            "file_id": send_id,
+            "size": filedata.len(),
+        });
+        webhook::send("upload_start", &event).await;
+        let start_instant = time::Instant::now();
+
+        output::info(format!("Sending {}...", what));
+        let (result, attempts) = send_file_with_retries(&peripheral, &writer, csend, csendfile, &mut notification_stream, filedata,
+            send_id, &transfer, upload_retries, verbosity).await;
+        let duration = start_instant.elapsed().as_secs_f64();
+        match &result {
+            Ok(Some(checksum)) => webhook::send("upload_finish", &webhook::with(&event, serde_json::json!({
+                "checksum": format!("{:08x}", checksum), "duration": duration, "attempts": attempts }))).await,
+            Ok(None) => webhook::send("upload_failure", &webhook::with(&event, serde_json::json!({
+                "error": "Transfer did not finish", "duration": duration, "attempts": attempts }))).await,
+            Err(err) => webhook::send("upload_failure", &webhook::with(&event, serde_json::json!({
+                "error": err.to_string(), "duration": duration, "attempts": attempts }))).await,
+        }
+        let finished = result?;
+        if finished.is_none() {
+            return Err(errors::coded("E020", format!("Upload didn't finish after {} attempt(s)", attempts)));
+        }
+        if mode == Mode::Upload {
+            // Switch to watch face feea2006190d --- number 13, the custom watch face we stored at file 0x74. File stored at 0x6e is in watch face #6.
+            writer.write(csend, &protocol::build(protocol::CMD_SWITCH_WATCH_FACE, &[ slot ]), WriteType::WithoutResponse).await?;
+        }
+        // The upload has worked by now, so problems with the journal are only warned about
+        #[cfg(feature = "journal")]
+        if let (Some(checksum), Some(path)) = (finished, &journal_file) {
+            let crc32 = match filedata.crc32() {
+                Ok(crc32) => Some(format!("{:08x}", crc32)),
+                Err(err) => {
+                    output::warning(format!("Unable to read {} again for the journal ({}).", filename, err));
+                    None
                 },
             };
+            let mut entry = webhook::with(&event, serde_json::json!({
+                "timestamp": chrono::Local::now().to_rfc3339(), "crc32": crc32, "checksum": format!("{:08x}", checksum) }));
+            if mode == Mode::Upload {
+                entry["slot"] = serde_json::json!(slot);
+            }
+            if let Some(d) = &device {
+                entry["device"]["alias"] = serde_json::json!(d.alias);
+            }
+            if let Err(err) = journal::record(path, &entry) {
+                output::warning(format!("{}.", err));
+            }
+        }
+        time::sleep(Duration::from_millis(1000)).await;
+    }
 
-            if verbosity > 0 {
-                println!("RECV: {}", data.iter().map(|c| format!("{:02x} ", c)).collect::<String>());
-            } 
+    // There's no known way to ask which face slots are in use or how much space is free, but we can ask which face is showing
+    if mode == Mode::Info {
+        let setting = settings::find("watch_face").ok_or("No watch_face setting")?;
+        match query_setting(&writer, csend, &mut notification_stream, setting, verbosity).await? {
+            Some(face) if face.as_integer() == Some(slot as i64) => output::info(format!("Watch Face:        {} (uploaded face)", face)),
+            Some(face) => output::info(format!("Watch Face:        {}", face)),
+            None => {
+                output::info("Watch Face:        (unknown)");
+                errors::report("E012", "The watch didn't answer when asked which face is showing.");
+            },
+        }
+        output::info(format!("Uploads go to:     slot {} (file {:02x})", slot, face_file_id(slot)?));
+    }
 
-            if data[0..5] == [ 0xfe, 0xea, 0x20, 0x09, 0x74 ] {             // All done
-                print!("\x0D{:<5.2} % ", 100);  // 100%
-                let checksum: u32 = u32::from_be_bytes(data[5..=8 ].try_into()?);
-                println!("All data recived by watch. Checksum: {:08x} ({})", checksum, checksum as i32);
+    // Terminate any transfer the watch is still waiting on, by sending the prep command with a size of zero
+    if mode == Mode::Abort {
+        let file_id = match file_id {
+            Some(id) => id,
+            None => face_file_id(slot)?,
+        };
+        output::info(format!("Cancelling transfer to file {:02x}...", file_id));
+        writer.write(csend, &protocol::build(file_id, &0u32.to_be_bytes()), WriteType::WithoutResponse).await?;
+        time::sleep(Duration::from_millis(1000)).await;
+    }
 
-                peripheral.write(csend, &[ 0xfe, 0xea, 0x20, 0x09, 0x74, 0x00, 0x00, 0x00, 0x00 ], WriteType::WithoutResponse).await?;
-                finished = true;
-            } else if data[0..5] == [ 0xfe, 0xea, 0x20, 0x07, 0x74 ] {      // Ready for chunk
-                let chunknum: usize = (u16::from_be_bytes(data[5..=6].try_into().unwrap())) as usize;                            
-                let startidx: usize = chunknum * CHUNKSIZE;
-                let mut endidx: usize = startidx + CHUNKSIZE;
+    if mode == Mode::SetTime {
+        set_time(&writer, csend, clock_correction, tz).await?;
+        time::sleep(Duration::from_millis(1000)).await;
+    }
 
-                if chunknum != expected_num {
-                    println!("WARNING: Expected request for chunk {}, got request for chunk {}", expected_num, chunknum);
-                }
-                expected_num = chunknum + 1;
-                if endidx > fsize as usize {
-                    endidx = fsize as usize;
-                }
-                if verbosity > 0 {
-                    println!("Sending chunk #{}", chunknum);
-                } else {
-                    let pc: f64 = (chunknum * CHUNKSIZE * 100) as f64/ (fsize as f64);
-                    print!("\x0D{:<5.2} % ", pc);
-                }
-                io::stdout().flush().unwrap();
-                peripheral.write(csendfile, &filedata[startidx..endidx], WriteType::WithoutResponse).await?;  // Send requested chunk
-            } else {
-                println!("WARNING: Unexpected data from watch!");
-            }
+    #[cfg(feature = "weather")]
+    if mode == Mode::Weather {
+        output::info("Sending weather...");
+        writer.write(csend, &protocol::build(protocol::CMD_SET_WEATHER_TODAY, &weather_payload), WriteType::WithoutResponse).await?;
+        time::sleep(Duration::from_millis(1000)).await;
+    }
+
+    // One of each, so it's easy to see which ones this watch shows
+    #[cfg(feature = "notify")]
+    if mode == Mode::Notify {
+        for (name, category) in notify::CATEGORIES {
+            output::info(format!("Sending {} notification...", name));
+            let text = format!("dawfu test: {}", name);
+            writer.write(csend, &protocol::build(protocol::CMD_SEND_MESSAGE, &notify::payload(category, &text)), WriteType::WithoutResponse).await?;
+            time::sleep(Duration::from_secs(3)).await;
         }
-        if finished {
-            println!("File send finished!");
-            // Switch to watch face feea2006190d --- number 13, the custom watch face we stored at file 0x74. File stored at 0x6e is in watch face #6.
-            peripheral.write(csend, &[0xfe, 0xea, 0x20, 0x06, 0x19, 0x0d ], WriteType::WithoutResponse).await?;  // send requested chunk
+    }
+
+    if mode == Mode::Settings && action == "apply" {
+        for (setting, payload) in &profile {
+            output::info(format!("Setting {}...", setting.name));
+            writer.write(csend, &protocol::build(setting.set, payload), WriteType::WithoutResponse).await?;
         }
+        output::success(format!("Applied {} settings.", profile.len()));
         time::sleep(Duration::from_millis(1000)).await;
     }
 
+    if mode == Mode::Settings && action == "show" {
+        output::info("Reading settings...");
+        for (setting, value) in query_settings(&writer, csend, &mut notification_stream, verbosity).await? {
+            match value {
+                Some(v) => output::info(format!("{} = {}", setting.name, v)),
+                None => output::warning(format!("# {} not supported", setting.name)),
+            }
+        }
+    }
+
+    if mode == Mode::Settings && action == "export" {
+        output::info("Reading settings...");
+        let values = query_settings(&writer, csend, &mut notification_stream, verbosity).await?;
+        let mut profile = format!("# dawfu settings from {} ({})\n", watch.name, watch.address);
+        for (setting, value) in &values {
+            match value {
+                Some(v) => profile += &format!("{} = {}\n", setting.name, v),
+                None => output::warning(format!("{} not supported, leaving it out.", setting.name)),
+            }
+        }
+        std::fs::write(&filename, profile).map_err(|e| format!("Unable to write profile {} ({})", filename, e))?;
+        output::success(format!("Saved {} settings to {}.", values.iter().filter(|(_, v)| v.is_some()).count(), filename));
+    }
+
+    #[cfg(feature = "listen")]
+    if mode == Mode::Listen {
+        output::info("Listening for the watch, press Ctrl-C to stop...");
+        while let Some(n) = notification_stream.next().await {
+            traffic_log::record(false, n.uuid, &n.value);
+            let packet = protocol::parse(&n.value);
+            match packet.as_ref().and_then(|p| events::decode(p.cmd, p.payload)) {
+                Some(event) => {
+                    output::info(format!("{}.", event));
+                    hooks.run(&event);
+                    if let Some(reply) = packet.and_then(|p| events::reply(p.cmd, p.payload)) {
+                        writer.write(csend, &reply, WriteType::WithoutResponse).await?;
+                    }
+                },
+                None if verbosity >= 1 => output::info(format!("{}: {}    {}", recv_label(n.uuid), protocol::to_hex(&n.value), protocol::describe(false, n.uuid, &n.value))),
+                None => (),
+            }
+        }
+        output::warning("The watch disconnected.");
+    }
+
+    if let Mode::Plugin(n) = mode {
+        let plugin = plugin::plugins()[n];
+        for (cmd, payload) in plugin.encode(&positional)? {
+            writer.write(csend, &protocol::build(cmd, &payload), WriteType::WithoutResponse).await?;
+        }
+        // Show any replies
+        while let Ok(Some(n)) = time::timeout(Duration::from_secs(2), notification_stream.next()).await {
+            traffic_log::record(false, n.uuid, &n.value);
+            output::info(format!("{}: {}    {}", recv_label(n.uuid), protocol::to_hex(&n.value), protocol::describe(false, n.uuid, &n.value)));
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    if mode == Mode::Script {
+        script::run_script(&filename, peripheral.clone(), writer.clone(), csend.clone(), notification_stream).await?;
+    }
+
     if peripheral.is_connected().await? {
-        println!("Disconnecting.");
+        output::info("Disconnecting.");
         peripheral
             .disconnect()
             .await
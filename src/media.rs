@@ -0,0 +1,58 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Media: the watch's music keys controlling a desktop media player (MPRIS on Linux)
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+
+
+//
+// Pass a music key (one of events::MUSIC_KEYS) to the media player
+//
+// Picks the player that's playing, or else the first one found. Blocks, so run it with spawn_blocking.
+//
+#[cfg(target_os = "linux")]
+pub fn press(key: &str) -> Result<(), Box<dyn Error>> {
+    use std::time::Duration;
+    use dbus::blocking::Connection;
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+
+    const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+    const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+    const MPRIS_PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+    const VOLUME_STEP: f64 = 0.1;
+    let timeout = Duration::from_secs(2);
+
+    let conn = Connection::new_session()?;
+    let bus = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", timeout);
+    let (names,): (Vec<String>,) = bus.method_call("org.freedesktop.DBus", "ListNames", ())?;
+    let players: Vec<String> = names.into_iter().filter(|n| n.starts_with(MPRIS_PREFIX)).collect();
+    let playing = players.iter().find(|name| {
+        let status: Result<String, _> = conn.with_proxy(name.as_str(), MPRIS_PATH, timeout).get(MPRIS_PLAYER, "PlaybackStatus");
+        status.is_ok_and(|s| s == "Playing")
+    });
+    let name = playing.or(players.first()).ok_or("No media player found")?;
+    let player = conn.with_proxy(name.as_str(), MPRIS_PATH, timeout);
+
+    let method = match key {
+        "play-pause" => "PlayPause",
+        "previous" => "Previous",
+        "next" => "Next",
+        "play" => "Play",
+        "pause" => "Pause",
+        "volume-up" | "volume-down" => {
+            let volume: f64 = player.get(MPRIS_PLAYER, "Volume")?;
+            let step = if key == "volume-up" { VOLUME_STEP } else { -VOLUME_STEP };
+            player.set(MPRIS_PLAYER, "Volume", (volume + step).clamp(0.0, 1.0))?;
+            return Ok(());
+        },
+        _ => return Ok(()),         // Nothing to do for reject-call
+    };
+    player.method_call::<(), _, _, _>(MPRIS_PLAYER, method, ())?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn press(_key: &str) -> Result<(), Box<dyn Error>> {
+    Err("Media player control is only built in on Linux, use on-music= instead".into())
+}
@@ -0,0 +1,35 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Notifications: message categories and payloads
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+
+//
+// Notification categories, which pick the icon shown on the watch
+//
+// Not every firmware knows every category, unknown ones may show as generic or not at all.
+//
+pub const CATEGORIES: [(&str, u8); 12] = [
+    ("call", 0), ("sms", 1), ("wechat", 2), ("qq", 3), ("facebook", 4), ("twitter", 5),
+    ("instagram", 6), ("skype", 7), ("whatsapp", 8), ("line", 9), ("kakao", 10), ("other", 11),
+];
+
+// The whole packet must fit in a single write, with its length in one byte
+const MAX_TEXT_BYTES: usize = 250 - 1;
+
+//
+// Payload for the send message command: the category, then the text in UTF-8
+//
+pub fn payload(category: u8, text: &str) -> Vec<u8> {
+    let mut end = text.len().min(MAX_TEXT_BYTES);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut payload = vec![ category ];
+    payload.extend_from_slice(&text.as_bytes()[..end]);
+    payload
+}
+
+pub fn category_name(category: u8) -> &'static str {
+    CATEGORIES.iter().find(|(_, c)| *c == category).map(|(name, _)| *name).unwrap_or("unknown")
+}
@@ -0,0 +1,149 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Output: coloured status messages, and the log file
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+static LOG_FILE: OnceLock<Mutex<LogFile>> = OnceLock::new();
+static PROGRESS_LOGGED: AtomicU32 = AtomicU32::new(0);     // Tenths of the transfer, for logging progress
+
+const LOG_MAX_SIZE: u64 = 1024 * 1024;      // Rotate the log file when it gets this big
+const LOG_KEEP: u32 = 3;                    // How many old log files to keep (dawfu.log.1 to dawfu.log.3)
+
+//
+// Turn colour on or off: auto (if stdout is a terminal and NO_COLOR isn't set), always, or never
+//
+pub fn set_color(choice: &str) -> Result<(), Box<dyn Error>> {
+    let enabled = match choice {
+        "auto" => env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && io::stdout().is_terminal(),
+        "always" => true,
+        "never" => false,
+        _ => return Err(format!("Invalid color option '{}', expected auto, always or never", choice).into()),
+    };
+    COLOR.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+fn paint(code: &str, msg: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", code, msg)
+    } else {
+        msg.to_string()
+    }
+}
+
+pub fn info(msg: impl Display) {
+    let msg = msg.to_string();
+    log("INFO ", &msg);
+    println!("{}", msg);
+}
+
+pub fn success(msg: impl Display) {
+    let msg = msg.to_string();
+    log("INFO ", &msg);
+    println!("{}", paint("32", &msg));
+}
+
+pub fn warning(msg: impl Display) {
+    let msg = msg.to_string();
+    log("WARN ", &msg);
+    println!("{}", paint("33", &msg));
+}
+
+pub fn error(msg: impl Display) {
+    let msg = msg.to_string();
+    log("ERROR", &msg);
+    eprintln!("{}", paint("31", &msg));
+}
+
+// Debug messages always go to the log file, but only to the screen with verbosity
+pub fn debug(verbosity: u32, msg: impl Display) {
+    let msg = msg.to_string();
+    log("DEBUG", &msg);
+    if verbosity > 0 {
+        println!("{}", paint("2", &msg));
+    }
+}
+
+// A prompt for the user, left on the same line as their answer
+pub fn prompt(msg: impl Display) {
+    let msg = msg.to_string();
+    log("INFO ", &msg);
+    print!("{}", msg);
+    let _ = io::stdout().flush();
+}
+
+// Transfer progress, shown in place. The log file gets every 10%.
+pub fn progress(percent: f64) {
+    let tenth = (percent / 10.0) as u32;
+    if PROGRESS_LOGGED.swap(tenth, Ordering::Relaxed) != tenth {
+        log("INFO ", &format!("{:.0} %", percent));
+    }
+    print!("\x0D{:<5.2} % ", percent);
+    let _ = io::stdout().flush();
+}
+
+
+//
+// Log file, rotated by size
+//
+struct LogFile {
+    filename: String,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    fn open(filename: &str) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(filename)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { filename: filename.to_string(), file, size })
+    }
+
+    // Move dawfu.log to dawfu.log.1, dawfu.log.1 to dawfu.log.2, etc. and start afresh
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..LOG_KEEP).rev() {
+            let from = format!("{}.{}", self.filename, n);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.filename, n + 1))?;
+            }
+        }
+        fs::rename(&self.filename, format!("{}.1", self.filename))?;
+        *self = LogFile::open(&self.filename)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size + line.len() as u64 > LOG_MAX_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+pub fn open_log(filename: &str) -> io::Result<()> {
+    let _ = LOG_FILE.set(Mutex::new(LogFile::open(filename)?));
+    Ok(())
+}
+
+pub fn log(level: &str, msg: &str) {
+    if let Some(log_file) = LOG_FILE.get() {
+        if let Ok(mut log_file) = log_file.lock() {
+            let line = format!("{} {} {}\n", chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), level, msg);
+            if let Err(err) = log_file.write_line(&line) {
+                eprintln!("Error writing log file ({}).", err);
+            }
+        }
+    }
+}
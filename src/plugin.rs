@@ -0,0 +1,45 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Plugins: extra commands for particular models, kept outside the core
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+
+
+// A command id and its payload
+pub type CommandPacket = (u8, Vec<u8>);
+
+//
+// A plugin adds a mode to dawfu, using the same scan, connect and write queue as everything else.
+//
+// To add one, implement Plugin in its own crate or a feature-gated module, and list it in plugins().
+//
+pub trait Plugin: Sync {
+    // Mode name on the command line, e.g. "dawfu <name> ..."
+    fn name(&self) -> &'static str;
+
+    // One line of help text
+    fn help(&self) -> &'static str;
+
+    // Turn the command-line arguments into (command, payload) packets to send
+    fn encode(&self, args: &[String]) -> Result<Vec<CommandPacket>, Box<dyn Error>>;
+
+    // Describe a packet, if it's one this plugin knows about
+    fn decode(&self, sent: bool, cmd: u8, payload: &[u8]) -> Option<String>;
+}
+
+//
+// All the plugins built into this binary
+//
+static PLUGINS: &[&dyn Plugin] = &[
+    #[cfg(feature = "find-watch")]
+    &crate::find_watch::FindWatch,
+];
+
+pub fn plugins() -> Vec<&'static dyn Plugin> {
+    PLUGINS.to_vec()
+}
+
+pub fn find(name: &str) -> Option<usize> {
+    plugins().iter().position(|p| p.name() == name)
+}
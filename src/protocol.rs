@@ -0,0 +1,201 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Protocol packets: parsing and description
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use uuid::Uuid;
+use crate::{CU_SEND, CU_SENDFILE, plugin, settings};
+#[cfg(feature = "listen")]
+use crate::events;
+#[cfg(feature = "notify")]
+use crate::notify;
+#[cfg(feature = "weather")]
+use crate::weather;
+
+
+//
+// Command ids
+//
+// For file transfers the command byte is the file id, e.g. 0x74 for watch face slot 13.
+//
+pub const CMD_SWITCH_WATCH_FACE: u8 = 0x19;
+pub const CMD_SYNC_TIME: u8 = 0x31;
+#[cfg(feature = "notify")]
+pub const CMD_SEND_MESSAGE: u8 = 0x41;
+pub const CMD_SET_WEATHER_TODAY: u8 = 0x43;
+#[cfg(feature = "listen")]
+pub const CMD_FIND_PHONE: u8 = 0x62;                 // From the watch
+#[cfg(feature = "listen")]
+pub const CMD_SHUTTER_AND_MUSIC: u8 = 0x66;          // From the watch
+
+
+//
+// A packet sent to or received from CU_SEND / CU_NOTIFY (or CU_NOTIFYX)
+//
+// Packets look like: fe ea 20 <total length> <command> <payload...>
+//
+pub struct Packet<'a> {
+    pub cmd: u8,
+    pub payload: &'a [u8],
+}
+
+pub fn parse(data: &[u8]) -> Option<Packet<'_>> {
+    if data.len() < 5 || data[0..2] != [ 0xfe, 0xea ] || data[3] as usize != data.len() {
+        return None;
+    }
+    Some(Packet { cmd: data[4], payload: &data[5..] })
+}
+
+//
+// What the watch says during a file transfer
+//
+// The numbers are big endian, except on some firmware builds which send them little endian,
+// so they are kept as bytes until we know which.
+//
+pub enum TransferReply {
+    ChunkRequest([u8; 2]),      // Ready for this chunk
+    Complete([u8; 4]),          // Got the whole file, with this checksum
+}
+
+pub fn transfer_reply(packet: &Packet<'_>, file_id: u8) -> Option<TransferReply> {
+    if packet.cmd != file_id {
+        return None;
+    }
+    match *packet.payload {
+        [ a, b ] => Some(TransferReply::ChunkRequest([ a, b ])),
+        [ a, b, c, d ] => Some(TransferReply::Complete([ a, b, c, d ])),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+    Big,
+    Little,
+}
+
+impl ByteOrder {
+    pub fn parse(s: &str) -> Option<ByteOrder> {
+        match s {
+            "big" => Some(ByteOrder::Big),
+            "little" => Some(ByteOrder::Little),
+            _ => None,
+        }
+    }
+
+    pub fn u16(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+        }
+    }
+
+    pub fn u32(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+// The length byte covers the 5 byte header too
+pub const MAX_PAYLOAD: usize = 255 - 5;
+
+// Payloads over MAX_PAYLOAD are a bug in the caller, so they panic rather than being sent with the wrong length
+pub fn build(cmd: u8, payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= MAX_PAYLOAD, "payload of {} bytes is too long for a packet", payload.len());
+    let mut data = vec![ 0xfe, 0xea, 0x20, (payload.len() + 5) as u8, cmd ];
+    data.extend_from_slice(payload);
+    data
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|c| format!("{:02x}", c)).collect::<String>()
+}
+
+#[cfg(feature = "trace")]
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+//
+// Describe what a packet means, as best we know
+//
+pub fn describe(sent: bool, characteristic: Uuid, data: &[u8]) -> String {
+    if characteristic == CU_SENDFILE {
+        return format!("File data, {} bytes", data.len());
+    }
+    let packet = match parse(data) {
+        Some(p) => p,
+        None => return "Unknown".to_string(),
+    };
+    // Settings have fixed command ids, and some have the same payload lengths as file transfers
+    let setting = settings::describe(sent, packet.cmd, packet.payload);
+    match (sent, packet.cmd, packet.payload.len()) {
+        (true, CMD_SWITCH_WATCH_FACE, 1) => format!("Switch to watch face {}", packet.payload[0]),
+        (true, CMD_SYNC_TIME, 5) => format!("Set time to {} (GMT+{})",
+            u32::from_be_bytes([ packet.payload[0], packet.payload[1], packet.payload[2], packet.payload[3] ]), packet.payload[4]),
+        #[cfg(feature = "notify")]
+        (true, CMD_SEND_MESSAGE, n) if n >= 1 => format!("Send {} notification \"{}\"",
+            notify::category_name(packet.payload[0]), String::from_utf8_lossy(&packet.payload[1..])),
+        #[cfg(feature = "weather")]
+        (true, CMD_SET_WEATHER_TODAY, n) if n >= 3 => format!("Set today's weather to {} ({}°C)",
+            weather::icon_name(packet.payload[1]), packet.payload[2] as i8),
+        (true, file_id, 4) if characteristic == CU_SEND && setting.is_none() => {
+            let size = u32::from_be_bytes([ packet.payload[0], packet.payload[1], packet.payload[2], packet.payload[3] ]);
+            if size == 0 {
+                format!("End transfer to file {:02x}", file_id)
+            } else {
+                format!("Prepare to send {} bytes to file {:02x}", size, file_id)
+            }
+        },
+        (false, file_id, 2) if setting.is_none() => format!("File {:02x} ready for chunk {}", file_id, u16::from_be_bytes([ packet.payload[0], packet.payload[1] ])),
+        (false, file_id, 4) if setting.is_none() => {
+            let checksum = u32::from_be_bytes([ packet.payload[0], packet.payload[1], packet.payload[2], packet.payload[3] ]);
+            format!("File {:02x} received, checksum {:08x}", file_id, checksum)
+        },
+        (_, cmd, _) => setting
+            .or_else(|| describe_event(sent, cmd, packet.payload))
+            .or_else(|| plugin::plugins().iter().find_map(|p| p.decode(sent, cmd, packet.payload)))
+            .unwrap_or_else(|| format!("Command {:02x}", cmd)),
+    }
+}
+
+// Things the watch tells us about on its own, like buttons pressed on it
+#[cfg(feature = "listen")]
+fn describe_event(sent: bool, cmd: u8, payload: &[u8]) -> Option<String> {
+    events::decode(cmd, payload).filter(|_| !sent).map(|e| e.to_string())
+}
+
+#[cfg(not(feature = "listen"))]
+fn describe_event(_sent: bool, _cmd: u8, _payload: &[u8]) -> Option<String> {
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CU_NOTIFY;
+
+    #[test]
+    fn settings_are_not_file_transfers() {
+        let send = |cmd, payload: &[u8]| describe(true, CU_SEND, &build(cmd, payload));
+        let recv = |cmd, payload: &[u8]| describe(false, CU_NOTIFY, &build(cmd, payload));
+        assert_eq!(send(0x16, &8000u32.to_be_bytes()), "Set goal to 8000");
+        assert_eq!(send(0x71, &[ 22, 0, 7, 0 ]), "Set dnd to \"22:00-07:00\"");
+        assert_eq!(send(0x72, &[ 7, 0, 22, 0 ]), "Set quick_view_time to \"07:00-22:00\"");
+        assert_eq!(recv(0x26, &8000u32.to_be_bytes()), "goal is 8000");
+        assert_eq!(recv(0x81, &[ 22, 0, 7, 0 ]), "dnd is \"22:00-07:00\"");
+        assert_eq!(recv(0x82, &[ 7, 0, 22, 0 ]), "quick_view_time is \"07:00-22:00\"");
+
+        // File transfers still show as such
+        assert_eq!(send(0x74, &35840u32.to_be_bytes()), "Prepare to send 35840 bytes to file 74");
+        assert_eq!(send(0x74, &0u32.to_be_bytes()), "End transfer to file 74");
+        assert_eq!(recv(0x74, &[ 0, 3 ]), "File 74 ready for chunk 3");
+        assert_eq!(recv(0x74, &[ 0x00, 0x12, 0xfa, 0x3c ]), "File 74 received, checksum 0012fa3c");
+    }
+}
@@ -0,0 +1,136 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Scripting: run rhai scripts against a connected watch
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use rhai::{Array, Dynamic, Engine, EvalAltResult};
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use tokio::time;
+use tokio_stream::StreamExt;
+use btleplug::api::{
+    Peripheral as _,
+    WriteType,
+    Characteristic,
+    bleuuid::uuid_from_u16,
+};
+use btleplug::platform::Peripheral;
+use crate::write_queue::WriteQueue;
+use crate::{output, protocol, traffic_log, NotificationStream};
+
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+fn to_script_err(err: impl std::fmt::Display) -> Box<EvalAltResult> {
+    err.to_string().into()
+}
+
+fn to_byte(value: i64, what: &str) -> ScriptResult<u8> {
+    u8::try_from(value).map_err(|_| to_script_err(format!("{} {} is out of range, expected 0 to 255", what, value)))
+}
+
+fn to_bytes(array: Array) -> ScriptResult<Vec<u8>> {
+    if array.len() > protocol::MAX_PAYLOAD {
+        return Err(to_script_err(format!("Payload of {} bytes is too long, the most is {}", array.len(), protocol::MAX_PAYLOAD)));
+    }
+    array.into_iter().map(|v| to_byte(v.as_int().map_err(to_script_err)?, "Payload byte")).collect()
+}
+
+//
+// Run a script file against the connected watch
+//
+// Scripts can use:
+//   send(cmd, [payload...])        Send a command packet
+//   next_notification(ms)          Wait for a notification, returns an array of bytes, or () on timeout
+//   read(uuid)                     Read a characteristic (by 16-bit uuid), returns an array of bytes
+//   battery()                      Read the battery level
+//   sleep(ms)                      Wait a while
+//
+pub async fn run_script(filename: &str, peripheral: Peripheral, writer: WriteQueue, csend: Characteristic,
+                        notification_stream: NotificationStream) -> Result<(), Box<dyn Error>> {
+    let script = std::fs::read_to_string(filename)?;
+    let notification_stream = Arc::new(Mutex::new(notification_stream));
+    let handle = Handle::current();
+    let mut engine = Engine::new();
+
+    {
+        let handle = handle.clone();
+        engine.register_fn("send", move |cmd: i64, payload: Array| -> ScriptResult<()> {
+            let data = protocol::build(to_byte(cmd, "Command")?, &to_bytes(payload)?);
+            handle.block_on(writer.write(&csend, &data, WriteType::WithoutResponse)).map_err(to_script_err)
+        });
+    }
+    {
+        let handle = handle.clone();
+        engine.register_fn("next_notification", move |ms: i64| -> ScriptResult<Dynamic> {
+            let next = handle.block_on(async {
+                let mut stream = notification_stream.lock().await;
+                time::timeout(Duration::from_millis(ms.max(0) as u64), stream.next()).await
+            });
+            match next {
+                Ok(Some(n)) => {
+                    traffic_log::record(false, n.uuid, &n.value);
+                    Ok(n.value.into_iter().map(|b| Dynamic::from_int(b as i64)).collect::<Array>().into())
+                },
+                Ok(None) => Err(to_script_err("Notification stream ended")),
+                Err(_) => Ok(Dynamic::UNIT),
+            }
+        });
+    }
+    {
+        let handle = handle.clone();
+        let peripheral = peripheral.clone();
+        engine.register_fn("read", move |short_uuid: i64| -> ScriptResult<Array> {
+            let uuid = uuid_from_u16(short_uuid as u16);
+            let c = peripheral.characteristics().into_iter().find(|c| c.uuid == uuid)
+                .ok_or_else(|| to_script_err(format!("No such characteristic {:04x}", short_uuid)))?;
+            let data = handle.block_on(peripheral.read(&c)).map_err(to_script_err)?;
+            Ok(data.into_iter().map(|b| Dynamic::from_int(b as i64)).collect())
+        });
+    }
+    {
+        let handle = handle.clone();
+        engine.register_fn("battery", move || -> ScriptResult<i64> {
+            let c = peripheral.characteristics().into_iter().find(|c| c.uuid == crate::CU_BATTERY)
+                .ok_or_else(|| to_script_err("No battery characteristic"))?;
+            let data = handle.block_on(peripheral.read(&c)).map_err(to_script_err)?;
+            data.first().map(|b| *b as i64).ok_or_else(|| to_script_err("Empty battery level"))
+        });
+    }
+    engine.register_fn("sleep", move |ms: i64| {
+        handle.block_on(time::sleep(Duration::from_millis(ms.max(0) as u64)));
+    });
+
+    output::info(format!("Running script {}...", filename));
+    tokio::task::block_in_place(|| engine.run(&script)).map_err(|e| e.to_string())?;
+    output::info("Script finished.");
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn array(values: &[i64]) -> Array {
+        values.iter().map(|v| Dynamic::from_int(*v)).collect()
+    }
+
+    #[test]
+    fn bytes_out_of_range() {
+        assert_eq!(to_bytes(array(&[ 0, 255 ])).unwrap(), vec![ 0, 255 ]);
+        assert!(to_bytes(array(&[ 256 ])).is_err());
+        assert!(to_bytes(array(&[ -1 ])).is_err());
+        assert!(to_byte(0x174, "Command").is_err());
+    }
+
+    #[test]
+    fn payload_too_long() {
+        assert_eq!(to_bytes(array(&[ 1; protocol::MAX_PAYLOAD ])).unwrap().len(), protocol::MAX_PAYLOAD);
+        assert!(to_bytes(array(&[ 1; protocol::MAX_PAYLOAD + 1 ])).is_err());
+    }
+}
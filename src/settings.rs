@@ -0,0 +1,179 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Settings: the watch's preferences, and how to read and write them
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::convert::TryFrom;
+use std::error::Error;
+use toml::Value;
+use crate::errors;
+
+
+//
+// How a setting's value is packed
+//
+pub enum Kind {
+    Number,                     // u32, big endian
+    Byte,                       // u8
+    Switch,                     // u8, 0 is off and 1 is on
+    Choice(&'static [&'static str]),    // u8, index into the list
+    TimeRange,                  // start hour, start minute, end hour, end minute, or all 0xff for off
+}
+
+pub struct Setting {
+    pub name: &'static str,
+    pub query: u8,              // Command to ask for the value, the watch replies with the same command
+    pub set: u8,                // Command to change the value
+    pub kind: Kind,
+}
+
+// A command to change a setting, and its payload
+pub type SettingCommand = (&'static Setting, Vec<u8>);
+
+pub static SETTINGS: [Setting; 8] = [
+    Setting { name: "goal",            query: 0x26, set: 0x16, kind: Kind::Number },
+    Setting { name: "time_format",     query: 0x27, set: 0x17, kind: Kind::Choice(&[ "12h", "24h" ]) },
+    Setting { name: "quick_view",      query: 0x28, set: 0x18, kind: Kind::Switch },
+    Setting { name: "watch_face",      query: 0x29, set: 0x19, kind: Kind::Byte },
+    Setting { name: "units",           query: 0x2a, set: 0x1a, kind: Kind::Choice(&[ "metric", "imperial" ]) },
+    Setting { name: "language",        query: 0x2b, set: 0x1b, kind: Kind::Byte },
+    Setting { name: "dnd",             query: 0x81, set: 0x71, kind: Kind::TimeRange },
+    Setting { name: "quick_view_time", query: 0x82, set: 0x72, kind: Kind::TimeRange },
+];
+
+impl Setting {
+    //
+    // Value from the watch's reply to a query, e.g. 8000 or "24h" or "22:00-07:00" or "off"
+    //
+    pub fn decode(&self, payload: &[u8]) -> Option<Value> {
+        let value = match &self.kind {
+            Kind::Number if payload.len() >= 4 => Value::Integer(u32::from_be_bytes([ payload[0], payload[1], payload[2], payload[3] ]) as i64),
+            Kind::Byte if !payload.is_empty() => Value::Integer(payload[0] as i64),
+            Kind::Switch if !payload.is_empty() => Value::Boolean(payload[0] != 0),
+            Kind::Choice(names) if !payload.is_empty() => match names.get(payload[0] as usize) {
+                Some(name) => Value::String(name.to_string()),
+                None => Value::Integer(payload[0] as i64),
+            },
+            // Some watches report an hour of 0xff when the range is turned off
+            Kind::TimeRange if payload.len() >= 4 && (payload[0] > 23 || payload[2] > 23) => Value::String("off".to_string()),
+            Kind::TimeRange if payload.len() >= 4 => Value::String(format!("{:02}:{:02}-{:02}:{:02}", payload[0], payload[1], payload[2], payload[3])),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    //
+    // Payload to set the value, from a profile value in the same form as decode() gives
+    //
+    pub fn encode(&self, value: &Value) -> Result<Vec<u8>, Box<dyn Error>> {
+        let bad = || format!("Invalid value {} for setting '{}'", value, self.name);
+        let payload = match (&self.kind, value) {
+            (Kind::Number, Value::Integer(n)) => u32::try_from(*n).map_err(|_| bad())?.to_be_bytes().to_vec(),
+            (Kind::Byte, Value::Integer(n)) => vec![ u8::try_from(*n).map_err(|_| bad())? ],
+            (Kind::Switch, Value::Boolean(b)) => vec![ *b as u8 ],
+            (Kind::Choice(names), Value::String(s)) => vec![ names.iter().position(|n| n == s).ok_or_else(bad)? as u8 ],
+            (Kind::Choice(_), Value::Integer(n)) => vec![ u8::try_from(*n).map_err(|_| bad())? ],
+            (Kind::TimeRange, Value::String(s)) if s == "off" => vec![ 0xff; 4 ],
+            (Kind::TimeRange, Value::String(s)) => parse_time_range(s).ok_or_else(bad)?.to_vec(),
+            _ => return Err(bad().into()),
+        };
+        Ok(payload)
+    }
+}
+
+// e.g. 22:00-07:00
+fn parse_time_range(s: &str) -> Option<[u8; 4]> {
+    let (start, end) = s.split_once('-')?;
+    let (start_h, start_m) = start.trim().split_once(':')?;
+    let (end_h, end_m) = end.trim().split_once(':')?;
+    let range = [ start_h.parse().ok()?, start_m.parse().ok()?, end_h.parse().ok()?, end_m.parse().ok()? ];
+    if range[0] > 23 || range[1] > 59 || range[2] > 23 || range[3] > 59 {
+        return None;
+    }
+    Some(range)
+}
+
+//
+// Read a profile: a TOML file of setting = value, as written by settings export
+//
+// Returns the commands to send, in the order of SETTINGS.
+//
+pub fn load_profile(filename: &str) -> Result<Vec<SettingCommand>, Box<dyn Error>> {
+    let text = std::fs::read_to_string(filename).map_err(|e| errors::coded("E031", format!("Unable to read profile {} ({})", filename, e)))?;
+    let table = text.parse::<toml::Table>().map_err(|e| errors::coded("E031", format!("Error in profile {} ({})", filename, e)))?;
+    profile_commands(&table)
+}
+
+pub fn profile_commands(table: &toml::Table) -> Result<Vec<SettingCommand>, Box<dyn Error>> {
+    if let Some(key) = table.keys().find(|k| !SETTINGS.iter().any(|s| s.name == *k)) {
+        let names: Vec<&str> = SETTINGS.iter().map(|s| s.name).collect();
+        return Err(errors::coded("E031", format!("Unknown setting '{}' in profile, expected one of: {}", key, names.join(", "))));
+    }
+    let mut commands = Vec::new();
+    for setting in SETTINGS.iter() {
+        if let Some(value) = table.get(setting.name) {
+            commands.push((setting, setting.encode(value)?));
+        }
+    }
+    Ok(commands)
+}
+
+pub fn find(name: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|s| s.name == name)
+}
+
+pub fn find_by_query(cmd: u8) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|s| s.query == cmd)
+}
+
+//
+// Describe a settings packet, for the traffic log and plugin replies
+//
+pub fn describe(sent: bool, cmd: u8, payload: &[u8]) -> Option<String> {
+    if let Some(setting) = SETTINGS.iter().find(|s| s.set == cmd).filter(|_| sent) {
+        return setting.decode(payload).map(|v| format!("Set {} to {}", setting.name, v));
+    }
+    let setting = find_by_query(cmd)?;
+    if sent {
+        Some(format!("Query {}", setting.name))
+    } else {
+        setting.decode(payload).map(|v| format!("{} is {}", setting.name, v))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode the payload, check the value, and check it encodes back to the same payload
+    fn roundtrip(name: &str, payload: &[u8], value: Value) {
+        let setting = find(name).unwrap();
+        assert_eq!(setting.decode(payload), Some(value.clone()));
+        assert_eq!(setting.encode(&value).unwrap(), payload);
+    }
+
+    #[test]
+    fn every_kind_roundtrips() {
+        roundtrip("goal", &[ 0, 0, 0x1f, 0x40 ], Value::Integer(8000));
+        roundtrip("language", &[ 3 ], Value::Integer(3));
+        roundtrip("quick_view", &[ 1 ], Value::Boolean(true));
+        roundtrip("quick_view", &[ 0 ], Value::Boolean(false));
+        roundtrip("time_format", &[ 1 ], Value::String("24h".to_string()));
+        roundtrip("time_format", &[ 5 ], Value::Integer(5));
+        roundtrip("dnd", &[ 22, 0, 7, 0 ], Value::String("22:00-07:00".to_string()));
+        roundtrip("dnd", &[ 0xff, 0xff, 0xff, 0xff ], Value::String("off".to_string()));
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        let goal = find("goal").unwrap();
+        assert!(goal.encode(&Value::Integer(-1)).is_err());
+        assert!(goal.encode(&Value::String("8000".to_string())).is_err());
+        assert!(find("time_format").unwrap().encode(&Value::String("13h".to_string())).is_err());
+        let dnd = find("dnd").unwrap();
+        assert!(dnd.encode(&Value::String("24:00-07:00".to_string())).is_err());
+        assert!(dnd.encode(&Value::String("255:255-255:255".to_string())).is_err());
+        assert_eq!(dnd.decode(&[ 22, 0 ]), None);
+    }
+}
@@ -0,0 +1,247 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Simulator: a pretend watch for testing file transfers, with faults on request
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use btleplug::api::{CharPropFlags, Characteristic, ValueNotification};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use crate::{CU_NOTIFY, CU_SEND, CU_SENDFILE, SU_FEEA, Connection, FileSource, NotificationStream, Transfer, protocol, send_file_with_retries};
+use crate::write_queue::WriteQueue;
+
+
+//
+// Things for the simulated watch to get wrong
+//
+#[derive(Default)]
+pub struct Faults {
+    pub drop_request: Option<u16>,      // Never ask for this chunk (the first time it's due)
+    pub repeat_request: Option<u16>,    // Ask for this chunk a second time, after it arrives
+    pub disconnect_at: Option<u16>,     // Disconnect instead of asking for this chunk (the first time it's due)
+    pub garbage_at: Option<u16>,        // Send a short, malformed frame before asking for this chunk
+    pub little_endian: bool,            // Send chunk numbers and the checksum little endian, like some firmware
+}
+
+// Where notifications go while the watch is connected
+type Notify = Arc<Mutex<Option<mpsc::UnboundedSender<ValueNotification>>>>;
+
+struct Watch {
+    notify: Notify,
+    faults: Faults,
+    file_id: u8,
+    file: Vec<u8>,
+    received: usize,
+    requested: u16,
+}
+
+impl Watch {
+    fn send(&self, data: Vec<u8>) {
+        if let Some(notify) = self.notify.lock().unwrap().as_ref() {
+            let _ = notify.send(ValueNotification { uuid: CU_NOTIFY, value: data });
+        }
+    }
+
+    // Ask for a chunk
+    fn ask(&mut self, chunk: u16) {
+        self.requested = chunk;
+        if self.faults.disconnect_at == Some(chunk) {
+            // Ends the notification stream, until the host reconnects
+            self.faults.disconnect_at = None;
+            *self.notify.lock().unwrap() = None;
+            return;
+        }
+        if self.faults.garbage_at == Some(chunk) {
+            self.faults.garbage_at = None;
+            self.send(vec![ 0xfe, 0xea ]);
+        }
+        if self.faults.drop_request == Some(chunk) {
+            self.faults.drop_request = None;
+            return;
+        }
+        let number = if self.faults.little_endian { chunk.to_le_bytes() } else { chunk.to_be_bytes() };
+        self.send(protocol::build(self.file_id, &number));
+    }
+
+    // Simple sum, standing in for whatever the real watch uses
+    fn checksum(&self) -> u32 {
+        self.file.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32))
+    }
+}
+
+//
+// The simulated watch's side of the connection, for reconnecting after a disconnect
+//
+pub struct Link {
+    notify: Notify,
+}
+
+impl Connection for Link {
+    async fn reconnect(&self) -> Result<Option<NotificationStream>, Box<dyn Error>> {
+        let mut notify = self.notify.lock().unwrap();
+        if notify.is_some() {
+            return Ok(None);
+        }
+        let (sender, notifications) = mpsc::unbounded_channel();
+        *notify = Some(sender);
+        Ok(Some(Box::pin(UnboundedReceiverStream::new(notifications))))
+    }
+}
+
+//
+// Start a simulated watch
+//
+// Returns the write queue and notification stream to hand to send_file, the link to reconnect
+// with, and a handle that gives the file the watch received once the write queue is dropped.
+//
+pub fn start(faults: Faults) -> (WriteQueue, NotificationStream, Link, JoinHandle<Vec<u8>>) {
+    let (writer, mut writes) = WriteQueue::detached();
+    let (sender, notifications) = mpsc::unbounded_channel();
+    let notify: Notify = Arc::new(Mutex::new(Some(sender)));
+    let chunk_size = Transfer::default().chunk_size;
+    let mut watch = Watch { notify: notify.clone(), faults, file_id: 0, file: Vec::new(), received: 0, requested: 0 };
+    let handle = tokio::spawn(async move {
+        while let Some((characteristic, data)) = writes.recv().await {
+            if characteristic.uuid == CU_SEND {
+                // Prep command: file id and size, or a size of zero to end or cancel
+                let packet = match protocol::parse(&data) {
+                    Some(p) if p.payload.len() == 4 => p,
+                    _ => continue,
+                };
+                let size = u32::from_be_bytes([ packet.payload[0], packet.payload[1], packet.payload[2], packet.payload[3] ]) as usize;
+                if size > 0 {
+                    watch.file_id = packet.cmd;
+                    watch.file = vec![ 0; size ];
+                    watch.received = 0;
+                    watch.ask(0);
+                }
+            } else if characteristic.uuid == CU_SENDFILE {
+                let start = watch.requested as usize * chunk_size;
+                let end = (start + data.len()).min(watch.file.len());
+                watch.file[start..end].copy_from_slice(&data[..end - start]);
+                watch.received = watch.received.max(end);
+                let next = if watch.faults.repeat_request == Some(watch.requested) {
+                    watch.faults.repeat_request = None;
+                    watch.requested
+                } else {
+                    watch.requested + 1
+                };
+                if watch.received >= watch.file.len() {
+                    let checksum = watch.checksum();
+                    let checksum = if watch.faults.little_endian { checksum.to_le_bytes() } else { checksum.to_be_bytes() };
+                    watch.send(protocol::build(watch.file_id, &checksum));
+                } else {
+                    watch.ask(next);
+                }
+            }
+        }
+        watch.file
+    });
+    let stream: NotificationStream = Box::pin(UnboundedReceiverStream::new(notifications));
+    (writer, stream, Link { notify }, handle)
+}
+
+fn characteristic(uuid: uuid::Uuid) -> Characteristic {
+    Characteristic { uuid, service_uuid: SU_FEEA, properties: CharPropFlags::WRITE_WITHOUT_RESPONSE }
+}
+
+//
+// Upload a file to the simulator, the same way main() does, with retries
+//
+// Returns the checksum the watch reported (if it finished), and how many attempts there were.
+//
+pub async fn upload(link: &Link, writer: &WriteQueue, stream: &mut NotificationStream, file: &[u8],
+                    transfer: &Transfer, retries: u32) -> (Option<u32>, u32) {
+    let mut source = FileSource::from_vec(file.to_vec());
+    let (result, attempts) = send_file_with_retries(link, writer, &characteristic(CU_SEND), &characteristic(CU_SENDFILE), stream, &mut source,
+        crate::FILE_ID_FACE, transfer, retries, 0).await;
+    (result.expect("send_file failed"), attempts)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file of a few chunks, not a whole number of them
+    fn test_file() -> Vec<u8> {
+        (0..2000u32).map(|n| (n * 7 % 251) as u8).collect()
+    }
+
+    fn sum(file: &[u8]) -> u32 {
+        file.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32))
+    }
+
+    // Upload with the default transfer and the given retries, and check the watch got the whole file
+    async fn check_upload(faults: Faults, retries: u32) -> (Option<u32>, u32) {
+        let file = test_file();
+        let transfer = Transfer::default();
+        let (writer, mut stream, link, watch) = start(faults);
+        let result = upload(&link, &writer, &mut stream, &file, &transfer, retries).await;
+        drop(writer);
+        if result.0.is_some() {
+            assert_eq!(watch.await.unwrap(), file);
+        }
+        result
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn upload_completes() {
+        assert_eq!(check_upload(Faults::default(), 0).await, (Some(sum(&test_file())), 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_request_without_retries() {
+        assert_eq!(check_upload(Faults { drop_request: Some(3), ..Faults::default() }, 0).await, (None, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_request_then_retry() {
+        assert_eq!(check_upload(Faults { drop_request: Some(3), ..Faults::default() }, 2).await, (Some(sum(&test_file())), 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn out_of_order_request() {
+        assert_eq!(check_upload(Faults { repeat_request: Some(2), ..Faults::default() }, 0).await, (Some(sum(&test_file())), 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnect_halfway_then_reconnect() {
+        let chunks = test_file().len().div_ceil(Transfer::default().chunk_size) as u16;
+        let faults = Faults { disconnect_at: Some(chunks / 2), ..Faults::default() };
+        assert_eq!(check_upload(faults, 1).await, (Some(sum(&test_file())), 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn malformed_frame_is_skipped() {
+        assert_eq!(check_upload(Faults { garbage_at: Some(1), ..Faults::default() }, 0).await, (Some(sum(&test_file())), 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn little_endian_firmware() {
+        assert_eq!(check_upload(Faults { little_endian: true, ..Faults::default() }, 0).await, (Some(sum(&test_file())), 1));
+    }
+
+    // One chunk never shows the byte order, so the checksum is read big endian unless byte_order is set
+    #[tokio::test(start_paused = true)]
+    async fn little_endian_single_chunk() {
+        let file: Vec<u8> = test_file()[..100].to_vec();
+        for (byte_order, expected) in [ (None, sum(&file).swap_bytes()), (Some(protocol::ByteOrder::Little), sum(&file)) ] {
+            let transfer = Transfer { byte_order, ..Transfer::default() };
+            let (writer, mut stream, link, _) = start(Faults { little_endian: true, ..Faults::default() });
+            assert_eq!(upload(&link, &writer, &mut stream, &file, &transfer, 0).await, (Some(expected), 1));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gentle_transfer() {
+        let file = test_file();
+        let transfer = Transfer::default().gentle();
+        let (writer, mut stream, link, watch) = start(Faults::default());
+        assert_eq!(upload(&link, &writer, &mut stream, &file, &transfer, 0).await, (Some(sum(&file)), 1));
+        drop(writer);
+        assert_eq!(watch.await.unwrap(), file);
+    }
+}
@@ -0,0 +1,313 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Traces: comparing our traffic logs with captures of the official app
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs;
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use uuid::Uuid;
+use crate::{CU_NOTIFY, CU_SEND, CU_SENDFILE, output, protocol};
+
+
+//
+// A packet to or from the watch
+//
+// Captures don't say which characteristic a write went to (only the handle), so packets are told apart
+// by what they look like: fe ea packets are commands, anything else sent is file data.
+//
+pub struct Frame {
+    pub sent: bool,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    fn characteristic(&self) -> Uuid {
+        match (self.sent, protocol::parse(&self.data).is_some()) {
+            (true, true) => CU_SEND,
+            (true, false) => CU_SENDFILE,
+            (false, _) => CU_NOTIFY,
+        }
+    }
+
+    // What has to be the same for two packets to line up: direction, command and payload length (or data length)
+    fn key(&self) -> String {
+        let direction = if self.sent { "send" } else { "recv" };
+        match protocol::parse(&self.data) {
+            Some(packet) => format!("{} {:02x} {}", direction, packet.cmd, packet.payload.len()),
+            None => format!("{} data {}", direction, self.data.len()),
+        }
+    }
+
+    fn show(&self) -> String {
+        format!("{} {}    {}", if self.sent { "SEND" } else { "RECV" }, protocol::to_hex(&self.data),
+            protocol::describe(self.sent, self.characteristic(), &self.data))
+    }
+}
+
+//
+// Load a dawfu traffic log (trafficlog=) or a btsnoop capture (e.g. Android's btsnoop_hci.log)
+//
+pub fn load(filename: &str) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let data = fs::read(filename).map_err(|e| format!("Unable to read {} ({})", filename, e))?;
+    let frames = if data.starts_with(BTSNOOP_MAGIC) {
+        load_btsnoop(&data)
+    } else {
+        load_traffic_log(&String::from_utf8_lossy(&data))
+    };
+    frames.map_err(|e| format!("Error in {} ({})", filename, e).into())
+}
+
+fn load_traffic_log(text: &str) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let mut frames = Vec::new();
+    for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let bad = || format!("line {} is not a traffic log entry", n + 1);
+        let entry: serde_json::Value = serde_json::from_str(line).map_err(|_| bad())?;
+        let sent = match entry["direction"].as_str() {
+            Some("send") => true,
+            Some("recv") => false,
+            _ => return Err(bad().into()),
+        };
+        let data = entry["data"].as_str().and_then(protocol::from_hex).ok_or_else(bad)?;
+        frames.push(Frame { sent, data });
+    }
+    Ok(frames)
+}
+
+
+//
+// btsnoop captures
+//
+// File header: "btsnoop\0", version (1) and datalink type, then records of
+// original length, included length, flags, cumulative drops, timestamp, then the packet.
+// All big endian. Flag bit 0 is set for received packets, bit 1 for commands and events.
+//
+const BTSNOOP_MAGIC: &[u8] = b"btsnoop\0";
+const DATALINK_HCI: u32 = 1001;             // Packets without the H4 type byte
+const DATALINK_H4: u32 = 1002;              // Packets with the H4 type byte first
+
+const H4_ACL: u8 = 0x02;
+const L2CAP_CID_ATT: u16 = 0x0004;
+const ATT_WRITE_REQUEST: u8 = 0x12;
+const ATT_WRITE_COMMAND: u8 = 0x52;
+const ATT_NOTIFICATION: u8 = 0x1b;
+const ATT_INDICATION: u8 = 0x1d;
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn load_btsnoop(data: &[u8]) -> Result<Vec<Frame>, Box<dyn Error>> {
+    let datalink = be_u32(data, 12).ok_or("btsnoop header is too short")?;
+    if datalink != DATALINK_HCI && datalink != DATALINK_H4 {
+        return Err(format!("unsupported btsnoop datalink type {}", datalink).into());
+    }
+    let mut frames = Vec::new();
+    let mut partial: HashMap<(bool, u16), Vec<u8>> = HashMap::new();     // L2CAP frames still being reassembled
+    let mut pos = 16;
+    while pos < data.len() {
+        let incl_len = be_u32(data, pos + 4).ok_or("truncated btsnoop record")? as usize;
+        let flags = be_u32(data, pos + 8).ok_or("truncated btsnoop record")?;
+        let packet = data.get(pos + 24..pos + 24 + incl_len).ok_or("truncated btsnoop record")?;
+        pos += 24 + incl_len;
+
+        let sent = flags & 1 == 0;
+        let acl = match datalink {
+            DATALINK_H4 if packet.first() == Some(&H4_ACL) => &packet[1..],
+            DATALINK_HCI if flags & 2 == 0 => packet,
+            _ => continue,
+        };
+        // ACL header: handle and packet boundary flags, then length
+        let (handle_flags, acl_len) = match (le_u16(acl, 0), le_u16(acl, 2)) {
+            (Some(h), Some(l)) => (h, l as usize),
+            _ => continue,
+        };
+        let fragment = match acl.get(4..4 + acl_len) {
+            Some(f) => f,
+            None => continue,
+        };
+        let key = (sent, handle_flags & 0x0fff);
+        let l2cap = if (handle_flags >> 12) & 0x3 == 0x1 {
+            // Continuation of an earlier fragment
+            match partial.get_mut(&key) {
+                Some(buf) => {
+                    buf.extend_from_slice(fragment);
+                    buf.clone()
+                },
+                None => continue,
+            }
+        } else {
+            partial.insert(key, fragment.to_vec());
+            fragment.to_vec()
+        };
+        // L2CAP header: length, then channel
+        let (l2cap_len, cid) = match (le_u16(&l2cap, 0), le_u16(&l2cap, 2)) {
+            (Some(l), Some(c)) => (l as usize, c),
+            _ => continue,
+        };
+        if l2cap.len() < 4 + l2cap_len {
+            continue;       // Wait for the rest
+        }
+        partial.remove(&key);
+        if cid != L2CAP_CID_ATT {
+            continue;
+        }
+        frames.extend(att_frame(sent, &l2cap[4..4 + l2cap_len]));
+    }
+    Ok(frames)
+}
+
+// Writes and notifications (opcode, handle, value), leaving out the rest of the ATT traffic
+fn att_frame(sent: bool, att: &[u8]) -> Option<Frame> {
+    let opcode = *att.first()?;
+    let value = att.get(3..)?;
+    match (sent, opcode) {
+        // Turning notifications on and off isn't part of the conversation with the watch
+        (true, ATT_WRITE_REQUEST) if value.len() == 2 && value[1] == 0 && value[0] <= 2 => None,
+        (true, ATT_WRITE_REQUEST) | (true, ATT_WRITE_COMMAND) => Some(Frame { sent, data: value.to_vec() }),
+        (false, ATT_NOTIFICATION) | (false, ATT_INDICATION) => Some(Frame { sent, data: value.to_vec() }),
+        _ => None,
+    }
+}
+
+
+//
+// Compare two traces, lining up packets by direction, command and length, then showing what differs
+//
+pub fn diff(ours_filename: &str, theirs_filename: &str) -> Result<(), Box<dyn Error>> {
+    let ours = load(ours_filename)?;
+    let theirs = load(theirs_filename)?;
+    output::info(format!("Comparing {} ({} packets) with {} ({} packets)...", ours_filename, ours.len(), theirs_filename, theirs.len()));
+
+    let our_keys: Vec<String> = ours.iter().map(|f| f.key()).collect();
+    let their_keys: Vec<String> = theirs.iter().map(|f| f.key()).collect();
+    let (mut same, mut different, mut only_ours, mut only_theirs) = (0, 0, 0, 0);
+    let mut run = 0;                // Identical packets not shown yet
+    let show_run = |run: &mut usize| {
+        if *run > 0 {
+            output::info(format!("  = {} packets the same", run));
+            *run = 0;
+        }
+    };
+    for op in capture_diff_slices(Algorithm::Myers, &our_keys, &their_keys) {
+        match op {
+            DiffOp::Equal { old_index, new_index, len } => {
+                for n in 0..len {
+                    let (a, b) = (&ours[old_index + n], &theirs[new_index + n]);
+                    // File data is expected to differ, only commands are compared byte by byte
+                    if a.data == b.data || protocol::parse(&a.data).is_none() {
+                        same += 1;
+                        run += 1;
+                    } else {
+                        different += 1;
+                        show_run(&mut run);
+                        output::warning(format!("  ~ {}", a.show()));
+                        output::warning(format!("    {}", b.show()));
+                    }
+                }
+            },
+            DiffOp::Delete { old_index, old_len, .. } => {
+                show_run(&mut run);
+                for frame in &ours[old_index..old_index + old_len] {
+                    only_ours += 1;
+                    output::warning(format!("  - {}", frame.show()));
+                }
+            },
+            DiffOp::Insert { new_index, new_len, .. } => {
+                show_run(&mut run);
+                for frame in &theirs[new_index..new_index + new_len] {
+                    only_theirs += 1;
+                    output::warning(format!("  + {}", frame.show()));
+                }
+            },
+            DiffOp::Replace { old_index, old_len, new_index, new_len } => {
+                show_run(&mut run);
+                for frame in &ours[old_index..old_index + old_len] {
+                    only_ours += 1;
+                    output::warning(format!("  - {}", frame.show()));
+                }
+                for frame in &theirs[new_index..new_index + new_len] {
+                    only_theirs += 1;
+                    output::warning(format!("  + {}", frame.show()));
+                }
+            },
+        }
+    }
+    show_run(&mut run);
+    output::info(format!("{} the same, {} different, {} only in {}, {} only in {}.",
+        same, different, only_ours, ours_filename, only_theirs, theirs_filename));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A btsnoop record (H4 datalink) carrying one ACL packet
+    fn record(sent: bool, handle_flags: u16, fragment: &[u8]) -> Vec<u8> {
+        let mut packet = vec![ H4_ACL ];
+        packet.extend_from_slice(&handle_flags.to_le_bytes());
+        packet.extend_from_slice(&(fragment.len() as u16).to_le_bytes());
+        packet.extend_from_slice(fragment);
+        let mut record = Vec::new();
+        record.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+        record.extend_from_slice(&(if sent { 0u32 } else { 1 }).to_be_bytes());
+        record.extend_from_slice(&[ 0; 12 ]);       // Drops and timestamp
+        record.extend_from_slice(&packet);
+        record
+    }
+
+    // An L2CAP frame on the ATT channel
+    fn att(opcode: u8, handle: u16, value: &[u8]) -> Vec<u8> {
+        let mut l2cap = Vec::new();
+        l2cap.extend_from_slice(&(3 + value.len() as u16).to_le_bytes());
+        l2cap.extend_from_slice(&L2CAP_CID_ATT.to_le_bytes());
+        l2cap.push(opcode);
+        l2cap.extend_from_slice(&handle.to_le_bytes());
+        l2cap.extend_from_slice(value);
+        l2cap
+    }
+
+    #[test]
+    fn btsnoop_fragments_and_cccd_writes() {
+        const CONNECTION: u16 = 0x0040;
+        const CONTINUATION: u16 = 0x1000;
+        let command = protocol::build(0x19, &[ 0x55; 20 ]);
+        let write = att(ATT_WRITE_COMMAND, 0x0010, &command);
+        let reply = protocol::build(0x74, &[ 0x00, 0x01 ]);
+
+        let mut capture = BTSNOOP_MAGIC.to_vec();
+        capture.extend_from_slice(&1u32.to_be_bytes());
+        capture.extend_from_slice(&DATALINK_H4.to_be_bytes());
+        capture.extend(record(true, CONNECTION, &att(ATT_WRITE_REQUEST, 0x0011, &[ 0x01, 0x00 ])));    // Turn on notifications
+        capture.extend(record(true, CONNECTION, &write[..10]));
+        capture.extend(record(true, CONNECTION | CONTINUATION, &write[10..]));
+        capture.extend(record(false, CONNECTION, &att(ATT_NOTIFICATION, 0x0012, &reply)));
+
+        let frames = load_btsnoop(&capture).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].sent);
+        assert_eq!(frames[0].data, command);
+        assert_eq!(frames[0].key(), "send 19 20");
+        assert!(!frames[1].sent);
+        assert_eq!(frames[1].data, reply);
+        assert_eq!(frames[1].key(), "recv 74 2");
+    }
+
+    #[test]
+    fn file_data_lines_up_by_length() {
+        let chunk = Frame { sent: true, data: vec![ 0x12; 244 ] };
+        let other = Frame { sent: true, data: vec![ 0x34; 244 ] };
+        let last = Frame { sent: true, data: vec![ 0x12; 100 ] };
+        assert_eq!(chunk.key(), other.key());
+        assert_ne!(chunk.key(), last.key());
+    }
+}
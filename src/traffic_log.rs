@@ -0,0 +1,48 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Traffic log: every packet sent and received, one JSON object per line
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use btleplug::api::bleuuid::BleUuid;
+use uuid::Uuid;
+use crate::protocol;
+
+
+static TRAFFIC_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+//
+// Start logging traffic to the given file (appending)
+//
+pub fn open(filename: &str) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(filename)?;
+    let _ = TRAFFIC_LOG.set(Mutex::new(file));
+    Ok(())
+}
+
+//
+// Record a packet, if logging is on
+//
+pub fn record(sent: bool, characteristic: Uuid, data: &[u8]) {
+    let log = match TRAFFIC_LOG.get() {
+        Some(log) => log,
+        None => return,
+    };
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+    let entry = serde_json::json!({
+        "timestamp": timestamp,
+        "direction": if sent { "send" } else { "recv" },
+        "characteristic": characteristic.to_short_string(),
+        "data": protocol::to_hex(data),
+        "meaning": protocol::describe(sent, characteristic, data),
+    });
+    if let Ok(mut file) = log.lock() {
+        if let Err(err) = writeln!(file, "{}", entry) {
+            eprintln!("Error writing traffic log ({}).", err);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Weather: mapping conditions to the watch's weather icons
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+use crate::config::Config;
+
+
+//
+// Weather icons on the watch
+//
+pub const WEATHER_CLOUDY: u8 = 0;
+pub const WEATHER_FOGGY: u8 = 1;
+pub const WEATHER_OVERCAST: u8 = 2;
+pub const WEATHER_RAINY: u8 = 3;
+pub const WEATHER_SNOWY: u8 = 4;
+pub const WEATHER_SUNNY: u8 = 5;
+pub const WEATHER_SANDSTORM: u8 = 6;
+pub const WEATHER_HAZE: u8 = 7;
+
+const ICON_NAMES: [&str; 8] = [ "cloudy", "foggy", "overcast", "rainy", "snowy", "sunny", "sandstorm", "haze" ];
+
+// Common condition descriptions
+const DEFAULT_CONDITIONS: [(&str, u8); 27] = [
+    ("clear", WEATHER_SUNNY), ("sunny", WEATHER_SUNNY), ("fair", WEATHER_SUNNY),
+    ("clouds", WEATHER_CLOUDY), ("cloudy", WEATHER_CLOUDY), ("partly cloudy", WEATHER_CLOUDY), ("mostly cloudy", WEATHER_CLOUDY),
+    ("overcast", WEATHER_OVERCAST),
+    ("rain", WEATHER_RAINY), ("rainy", WEATHER_RAINY), ("showers", WEATHER_RAINY), ("drizzle", WEATHER_RAINY),
+    ("thunderstorm", WEATHER_RAINY), ("storm", WEATHER_RAINY),
+    ("snow", WEATHER_SNOWY), ("snowy", WEATHER_SNOWY), ("sleet", WEATHER_SNOWY), ("hail", WEATHER_SNOWY),
+    ("fog", WEATHER_FOGGY), ("foggy", WEATHER_FOGGY), ("mist", WEATHER_FOGGY),
+    ("haze", WEATHER_HAZE), ("smoke", WEATHER_HAZE),
+    ("dust", WEATHER_SANDSTORM), ("sand", WEATHER_SANDSTORM), ("sandstorm", WEATHER_SANDSTORM), ("ash", WEATHER_HAZE),
+];
+
+//
+// OpenWeatherMap condition codes (https://openweathermap.org/weather-conditions)
+//
+fn owm_icon(code: u32) -> Option<u8> {
+    let icon = match code {
+        200..=299 => WEATHER_RAINY,     // Thunderstorm
+        300..=399 => WEATHER_RAINY,     // Drizzle
+        511 => WEATHER_SNOWY,           // Freezing rain
+        500..=599 => WEATHER_RAINY,     // Rain
+        600..=699 => WEATHER_SNOWY,     // Snow
+        701 | 741 => WEATHER_FOGGY,     // Mist, fog
+        711 | 721 | 762 => WEATHER_HAZE,    // Smoke, haze, volcanic ash
+        731 | 751 | 761 => WEATHER_SANDSTORM,   // Dust, sand
+        771 | 781 => WEATHER_RAINY,     // Squalls, tornado
+        800 => WEATHER_SUNNY,           // Clear
+        801 | 802 => WEATHER_CLOUDY,    // Few or scattered clouds
+        803 | 804 => WEATHER_OVERCAST,  // Broken or overcast clouds
+        _ => return None,
+    };
+    Some(icon)
+}
+
+//
+// Find the watch icon for a condition: a description (e.g. "light rain") or an OpenWeatherMap code (e.g. 500)
+//
+// The [weather] section of the config file overrides the defaults, e.g.
+//     [weather]
+//     "light rain" = "overcast"
+//     804 = 0
+//
+pub fn condition_icon(config: &Config, condition: &str) -> Result<u8, Box<dyn Error>> {
+    let key = condition.trim().to_lowercase();
+    if let Some(value) = config.section("weather").and_then(|t| t.iter().find(|(k, _)| k.to_lowercase() == key).map(|(_, v)| v)) {
+        return match value {
+            toml::Value::Integer(n) if (*n as usize) < ICON_NAMES.len() && *n >= 0 => Ok(*n as u8),
+            toml::Value::String(s) => icon_by_name(s).ok_or_else(|| format!("Unknown weather icon '{}' in config file", s).into()),
+            _ => Err(format!("Invalid weather icon for '{}' in config file", condition).into()),
+        };
+    }
+    if let Ok(code) = key.parse::<u32>() {
+        return owm_icon(code).ok_or_else(|| format!("Unknown weather condition code {}", code).into());
+    }
+    if let Some(icon) = icon_by_name(&key) {
+        return Ok(icon);
+    }
+    // Try the words on their own, e.g. "light rain" is rain
+    DEFAULT_CONDITIONS.iter().find(|(name, _)| key.split_whitespace().any(|w| w == *name)).map(|(_, icon)| *icon)
+        .ok_or_else(|| format!("Unknown weather condition '{}', add it to the [weather] section of the config file", condition).into())
+}
+
+fn icon_by_name(name: &str) -> Option<u8> {
+    let name = name.to_lowercase();
+    ICON_NAMES.iter().position(|n| *n == name).map(|n| n as u8)
+        .or_else(|| DEFAULT_CONDITIONS.iter().find(|(n, _)| *n == name).map(|(_, icon)| *icon))
+}
+
+pub fn icon_name(icon: u8) -> &'static str {
+    ICON_NAMES.get(icon as usize).copied().unwrap_or("unknown")
+}
+
+//
+// Payload for today's weather: festival flag, icon, temperature (C), then the first 4 characters of the city in UTF-16BE
+//
+pub fn today_payload(icon: u8, temperature: i8, city: &str) -> Vec<u8> {
+    let mut payload = vec![ 0, icon, temperature as u8 ];
+    for c in city.encode_utf16().take(4) {
+        payload.extend_from_slice(&c.to_be_bytes());
+    }
+    payload
+}
@@ -0,0 +1,64 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Webhook: POSTing JSON events, so other tools can react to uploads
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+use std::sync::OnceLock;
+use serde_json::Value;
+use crate::errors;
+#[cfg(feature = "http")]
+use crate::output;
+
+
+static WEBHOOK_URL: OnceLock<String> = OnceLock::new();
+
+pub fn set_url(url: &str) -> Result<(), Box<dyn Error>> {
+    if cfg!(not(feature = "http")) {
+        return Err(errors::coded("E040", "dawfu was built without the http feature, so can't use webhooks"));
+    }
+    if !crate::is_url(url) {
+        return Err(format!("Invalid webhook URL '{}', expected http:// or https://", url).into());
+    }
+    let _ = WEBHOOK_URL.set(url.to_string());
+    Ok(())
+}
+
+//
+// The fields of an event, plus some more
+//
+pub fn with(fields: &Value, more: Value) -> Value {
+    let mut fields = fields.clone();
+    if let (Some(fields), Value::Object(more)) = (fields.as_object_mut(), more) {
+        fields.extend(more);
+    }
+    fields
+}
+
+//
+// POST an event, if there's a webhook. Failures are only warned about, so they don't stop the upload.
+//
+#[cfg(feature = "http")]
+pub async fn send(event: &str, fields: &Value) {
+    let url = match WEBHOOK_URL.get() {
+        Some(url) => url.clone(),
+        None => return,
+    };
+    let body = with(&serde_json::json!({ "event": event, "timestamp": chrono::Local::now().to_rfc3339() }), fields.clone());
+    match tokio::task::spawn_blocking(move || post(&url, &body)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => output::warning(format!("Webhook failed ({}).", err)),
+        Err(err) => output::warning(format!("Webhook failed ({}).", err)),
+    }
+}
+
+#[cfg(feature = "http")]
+fn post(url: &str, body: &Value) -> Result<(), String> {
+    ureq::post(url).timeout(std::time::Duration::from_secs(10)).set("Content-Type", "application/json")
+        .send_string(&body.to_string()).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Without http, set_url() never sets a webhook
+#[cfg(not(feature = "http"))]
+pub async fn send(_event: &str, _fields: &Value) {}
@@ -0,0 +1,135 @@
+// dawfu: Da Watch Face Uploader - Face Uploader for MO YOUNG / DA FIT Smart Watches
+// Write queue: all characteristic writes go through here
+// Copyright 2022 David Atkinson <david@47k@d47.co> (remove the first @)
+// MIT License
+
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+use btleplug::api::{
+    Peripheral as _,
+    WriteType,
+    Characteristic,
+};
+use btleplug::platform::Peripheral;
+use crate::traffic_log;
+
+
+const QUEUE_DEPTH: usize = 8;                               // Maximum number of writes waiting to go out
+const RETRY_PAUSE: Duration = Duration::from_millis(25);    // First pause after a transient error, doubling each time
+const RETRY_PAUSE_MAX: Duration = Duration::from_secs(1);   // Longest pause between attempts
+const WRITE_ATTEMPTS: u32 = 10;                             // How many times to try a write before giving up
+
+
+struct WriteRequest {
+    characteristic: Characteristic,
+    data: Vec<u8>,
+    write_type: WriteType,
+    done: oneshot::Sender<Result<(), btleplug::Error>>,
+}
+
+//
+// Queue of characteristic writes
+//
+// Writes are sent one at a time, in order. Callers wait when the queue is full,
+// and the queue backs off and tries again when a write fails with a transient error.
+//
+#[derive(Clone)]
+pub struct WriteQueue {
+    tx: mpsc::Sender<WriteRequest>,
+    retries: Arc<AtomicU32>,
+}
+
+impl WriteQueue {
+    pub fn new(peripheral: Peripheral) -> WriteQueue {
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        let retries = Arc::new(AtomicU32::new(0));
+        tokio::spawn(run_queue(peripheral, rx, retries.clone()));
+        WriteQueue { tx, retries }
+    }
+
+    // Number of write attempts that had to be retried
+    pub fn retries(&self) -> u32 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    // A queue whose writes go to a channel instead of a watch, for the simulator
+    #[cfg(test)]
+    pub fn detached() -> (WriteQueue, mpsc::UnboundedReceiver<(Characteristic, Vec<u8>)>) {
+        let (tx, mut rx) = mpsc::channel::<WriteRequest>(QUEUE_DEPTH);
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let _ = out_tx.send((request.characteristic, request.data));
+                let _ = request.done.send(Ok(()));
+            }
+        });
+        (WriteQueue { tx, retries: Arc::new(AtomicU32::new(0)) }, out_rx)
+    }
+
+    // Queue a write and wait until it has been sent
+    pub async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> Result<(), Box<dyn Error>> {
+        let (done, done_rx) = oneshot::channel();
+        let request = WriteRequest { characteristic: characteristic.clone(), data: data.to_vec(), write_type, done };
+        self.tx.send(request).await.map_err(|_| "Write queue has stopped")?;
+        done_rx.await.map_err(|_| "Write queue has stopped")??;
+        Ok(())
+    }
+}
+
+//
+// Check if a write error is worth trying again: the OS or the watch telling us to slow down
+//
+// Only BlueZ says so in a way that can be told apart: "In Progress" while another operation on the
+// device finishes, and the ATT errors Unlikely Error (0x0e) and Insufficient Resources (0x11). btleplug
+// on Windows and macOS passes on nothing more specific than a failed write, so those aren't retried.
+//
+fn is_transient(err: &btleplug::Error) -> bool {
+    match err {
+        btleplug::Error::Other(inner) => is_transient_platform(inner.as_ref()),
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_transient_platform(err: &(dyn Error + Send + Sync + 'static)) -> bool {
+    const ATT_TRANSIENT: [&str; 2] = [ "Operation failed with ATT error: 0x0e", "Operation failed with ATT error: 0x11" ];
+    match err.downcast_ref::<bluez_async::BluetoothError>() {
+        Some(bluez_async::BluetoothError::DbusError(e)) => match (e.name(), e.message()) {
+            (Some("org.bluez.Error.InProgress"), _) => true,
+            (Some("org.bluez.Error.Failed"), Some(message)) => ATT_TRANSIENT.contains(&message),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_transient_platform(_err: &(dyn Error + Send + Sync + 'static)) -> bool {
+    false
+}
+
+async fn run_queue(peripheral: Peripheral, mut rx: mpsc::Receiver<WriteRequest>, retries: Arc<AtomicU32>) {
+    while let Some(request) = rx.recv().await {
+        let mut attempt = 1;
+        let mut pause = RETRY_PAUSE;
+        let result = loop {
+            match peripheral.write(&request.characteristic, &request.data, request.write_type).await {
+                Err(err) if is_transient(&err) && attempt < WRITE_ATTEMPTS => {
+                    attempt += 1;
+                    retries.fetch_add(1, Ordering::Relaxed);
+                    time::sleep(pause).await;
+                    pause = (pause * 2).min(RETRY_PAUSE_MAX);
+                },
+                result => break result,
+            }
+        };
+        if result.is_ok() {
+            traffic_log::record(true, request.characteristic.uuid, &request.data);
+        }
+        let _ = request.done.send(result);  // The caller may have given up waiting
+    }
+}